@@ -0,0 +1,118 @@
+use std::hint::black_box;
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use stitcher::{bundle, bundle_set, config, output, synthetic};
+
+/// One large project, as the server manual is, alongside a few small ones.
+fn generators() -> Vec<synthetic::Generator> {
+    let mut generators = vec![synthetic::Generator {
+        pages: 1000,
+        ..synthetic::Generator::new("manual")
+    }];
+    for project in ["atlas", "compass", "charts"] {
+        generators.push(synthetic::Generator::new(project));
+    }
+
+    generators
+}
+
+fn write_bundles(root: &Path) -> Vec<PathBuf> {
+    std::fs::create_dir_all(root).unwrap();
+    generators()
+        .into_iter()
+        .map(|generator| {
+            let path = root.join(format!("{}.zip", generator.project));
+            generator
+                .write(output::ZipSink::create(&path).unwrap())
+                .unwrap();
+            path
+        })
+        .collect()
+}
+
+fn open_bundles(paths: &[PathBuf]) -> bundle_set::BundleSet {
+    let bundles: Vec<bundle::Bundle> = paths
+        .iter()
+        .map(|path| bundle::Bundle::open(path).unwrap())
+        .collect();
+    bundle_set::BundleSet::new(bundles.into_iter(), config::Config::default())
+}
+
+fn n_pages() -> u64 {
+    generators()
+        .iter()
+        .map(|generator| generator.pages as u64)
+        .sum()
+}
+
+fn stitch(c: &mut Criterion) {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    let paths = write_bundles(root);
+
+    let mut group = c.benchmark_group("stitch");
+    group.sample_size(10);
+
+    let mut bundle = bundle::Bundle::open(&paths[0]).unwrap();
+    group.throughput(Throughput::Elements(generators()[0].pages as u64));
+    group.bench_function("iterate_largest_bundle", |b| {
+        b.iter(|| {
+            for element in &mut bundle {
+                black_box(element.unwrap());
+            }
+        })
+    });
+
+    let mut bundles = open_bundles(&paths);
+    group.throughput(Throughput::Elements(n_pages()));
+    group.bench_function("link", |b| b.iter(|| bundles.link().unwrap()));
+
+    let site_metadata = bundle::SiteMetadata::new("mongodb", "main");
+    group.bench_function("splice", |b| {
+        b.iter(|| {
+            let sink = output::ZipSink::create(&root.join("out.zip")).unwrap();
+            bundles
+                .splice(&site_metadata, None, None, bundle::OutputFormat::Bson, sink)
+                .unwrap();
+        })
+    });
+    group.finish();
+}
+
+/// Compare migrating a document by decoding and re-encoding it against patching its raw BSON.
+fn migrate(c: &mut Criterion) {
+    let data = std::fs::read("test_data/supported-operations.bson").unwrap();
+    let path = PathBuf::from("documents/supported-operations.bson");
+    let namespace = Path::new("manual/main");
+
+    let mut group = c.benchmark_group("migrate");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("decoded", |b| {
+        b.iter(|| {
+            let entry = bundle::RawEntry {
+                path: path.clone(),
+                data: data.clone(),
+            };
+            let mut element = entry.decode().unwrap().unwrap();
+            element.migrate(namespace);
+            if let bundle::BundleElementData::Document(doc) = element.data {
+                bson::to_vec(&doc).unwrap();
+            }
+        })
+    });
+    group.bench_function("raw", |b| {
+        b.iter(|| {
+            let mut entry = bundle::RawEntry {
+                path: path.clone(),
+                data: data.clone(),
+            };
+            entry.migrate(namespace).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, stitch, migrate);
+criterion_main!(benches);
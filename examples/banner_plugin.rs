@@ -0,0 +1,72 @@
+//! A reference stitcher plugin which adds a banner to the top of every page. If a label is
+//! given after the message, the banner is preceded by a target of that label, so that other
+//! pages can link to it.
+//!
+//! Stitcher writes each document to a plugin's stdin as a BSON document, and expects the
+//! transformed document back on stdout, one at a time and in order. Configure it with:
+//!
+//! ```toml
+//! [[plugins]]
+//! name = "banner"
+//! command = ["target/debug/examples/banner_plugin", "This page is a preview"]
+//! ```
+
+use std::io::{BufReader, BufWriter, Write};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let message = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "This page is a preview".to_owned());
+    let label = std::env::args().nth(2);
+    let position = bson::doc! {"start": {"line": 0}};
+
+    let mut stdin = BufReader::new(std::io::stdin().lock());
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+
+    loop {
+        let mut document = match bson::Document::from_reader(&mut stdin) {
+            Ok(document) => document,
+            // The stream ends when stitcher has no more documents to send
+            Err(bson::de::Error::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let banner = bson::doc! {
+            "type": "directive",
+            "position": position.clone(),
+            "domain": "",
+            "name": "banner",
+            "argument": [],
+            "options": {"variant": "info"},
+            "children": [{
+                "type": "paragraph",
+                "position": position.clone(),
+                "children": [{"type": "text", "position": position.clone(), "value": &message}],
+            }],
+        };
+        let children = document
+            .get_document_mut("ast")?
+            .get_array_mut("children")?;
+        children.insert(0, bson::Bson::Document(banner));
+        if let Some(label) = &label {
+            let target = bson::doc! {
+                "type": "target",
+                "position": position.clone(),
+                "domain": "std",
+                "name": "label",
+                "children": [{
+                    "type": "target_identifier",
+                    "position": position.clone(),
+                    "ids": [label],
+                    "children": [],
+                }],
+            };
+            children.insert(0, bson::Bson::Document(target));
+        }
+
+        document.to_writer(&mut stdout)?;
+        stdout.flush()?;
+    }
+}
@@ -1,27 +1,11 @@
-use std::borrow::Cow;
-use std::collections::HashMap;
 use std::sync::Mutex;
 
+use crate::bundle;
+use crate::html_ids;
 use crate::nodes;
 use crate::target_database;
 
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref PAT_INVALID_ID_CHARACTERS: regex::Regex =
-        regex::Regex::new(r###"[^\w_\.\-]"###).unwrap();
-}
-
-/// Turn an ID into a valid HTML5 element ID.
-fn make_html5_id(orig: &str) -> Cow<'_, str> {
-    let clean_id = PAT_INVALID_ID_CHARACTERS.replace_all(orig, "-");
-    if clean_id.is_empty() {
-        return Cow::from("unnamed");
-    }
-
-    clean_id
-}
-
+#[derive(Default)]
 pub struct FileIdStack {
     stack: Vec<nodes::FileId>,
 }
@@ -52,12 +36,228 @@ impl FileIdStack {
     }
 }
 
+/// The heading of a section enclosing the node being analyzed.
+#[derive(Debug, Clone)]
+pub struct HeadingInfo {
+    pub id: String,
+
+    /// Only gathered for analyzers which ask for it with [`Analyzer::needs_heading_titles`]
+    pub title: Option<String>,
+}
+
+/// A summary of an ancestor of the node being analyzed. Analyzers receive mutable access to
+/// nodes, so ancestors cannot be borrowed directly.
+#[derive(Debug, Clone)]
+pub struct Ancestor {
+    pub node_type: &'static str,
+
+    /// For sections, the section's heading
+    pub heading: Option<HeadingInfo>,
+}
+
+/// The state of a traversal: the stack of files being analyzed, and the ancestors of the
+/// current node.
+#[derive(Default)]
+pub struct AnalyzerContext {
+    pub fileid_stack: FileIdStack,
+    ancestors: Vec<Ancestor>,
+
+    /// Whether to gather the text of each heading, which is costly on every traversal
+    pub(crate) heading_titles: bool,
+}
+
+impl AnalyzerContext {
+    pub fn new() -> Self {
+        Self {
+            fileid_stack: FileIdStack::new(),
+            ancestors: vec![],
+            heading_titles: false,
+        }
+    }
+
+    /// A context for running the given analyzer.
+    pub(crate) fn for_analyzer(analyzer: &impl Analyzer) -> Self {
+        Self {
+            heading_titles: analyzer.needs_heading_titles(),
+            ..Self::new()
+        }
+    }
+
+    pub fn get_root(&self) -> Option<&nodes::FileId> {
+        self.fileid_stack.get_root()
+    }
+
+    pub fn get_current(&self) -> Option<&nodes::FileId> {
+        self.fileid_stack.get_current()
+    }
+
+    /// The ancestors of the current node, outermost first.
+    pub fn ancestors(&self) -> &[Ancestor] {
+        &self.ancestors
+    }
+
+    pub fn parent(&self) -> Option<&Ancestor> {
+        self.ancestors.last()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.ancestors.len()
+    }
+
+    /// The heading of the innermost enclosing section that has one.
+    pub fn enclosing_heading(&self) -> Option<&HeadingInfo> {
+        self.ancestors
+            .iter()
+            .rev()
+            .find_map(|ancestor| ancestor.heading.as_ref())
+    }
+
+    /// Enter a node's children. A section's heading is found up front, so that it encloses
+    /// every node within the section, including those which precede the heading. The title
+    /// is only computed if the analyzer asked for heading titles.
+    pub(crate) fn push_ancestor(&mut self, node: &nodes::Node) {
+        let heading = match node.data {
+            nodes::NodeData::Section(_) => {
+                node.data
+                    .children()
+                    .iter()
+                    .find_map(|child| match &child.data {
+                        nodes::NodeData::Heading(heading) => Some(HeadingInfo {
+                            id: heading.id.to_owned(),
+                            title: self.heading_titles.then(|| child.get_text()),
+                        }),
+                        _ => None,
+                    })
+            }
+            _ => None,
+        };
+
+        self.ancestors.push(Ancestor {
+            node_type: node.data.type_name(),
+            heading,
+        });
+    }
+
+    pub(crate) fn pop_ancestor(&mut self) {
+        self.ancestors.pop();
+    }
+}
+
 pub trait Analyzer {
-    fn enter_page(&mut self, _fileid_stack: &FileIdStack, _page: &nodes::Document) {}
-    fn exit_page(&mut self, _fileid_stack: &FileIdStack, _page: &nodes::Document) {}
+    fn enter_page(&mut self, _context: &AnalyzerContext, _page: &nodes::Document) {}
+    fn exit_page(&mut self, _context: &AnalyzerContext, _page: &nodes::Document) {}
+
+    fn enter_node(&mut self, _context: &AnalyzerContext, _node: &mut nodes::Node) {}
+    fn exit_node(&mut self, _context: &AnalyzerContext, _node: &mut nodes::Node) {}
+
+    /// Whether this analyzer reads [`HeadingInfo::title`]. Gathering titles means walking
+    /// the text of every heading, so it is off unless asked for.
+    fn needs_heading_titles(&self) -> bool {
+        false
+    }
+
+    /// Take the diagnostics raised about the pages analyzed since this was last called, to
+    /// be written alongside the page's other diagnostics.
+    fn take_diagnostics(&mut self) -> Vec<bundle::Diagnostic> {
+        vec![]
+    }
+}
+
+/// What a [`Transform`] should do with the node it was given.
+pub enum Action {
+    /// Leave the node in place, and transform its children
+    Keep,
+
+    /// Replace the node with zero or more nodes, which are not themselves transformed
+    Replace(Vec<nodes::Node>),
+
+    /// Remove the node and all of its children
+    Remove,
+
+    /// Replace the node with its children, which are then transformed in turn. Nodes which
+    /// cannot have children, such as Text, are kept as they are.
+    Unwrap,
+}
+
+/// A pass which may restructure the tree, rather than only modifying nodes in place.
+pub trait Transform {
+    fn transform_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) -> Action;
+}
+
+/// The state of a read-only traversal. Unlike [`AnalyzerContext`], the ancestors of the
+/// current node are directly available.
+#[derive(Default)]
+pub struct VisitorContext<'a> {
+    fileids: Vec<&'a nodes::FileId>,
+    ancestors: Vec<&'a nodes::Node>,
+}
+
+impl<'a> VisitorContext<'a> {
+    pub fn new() -> Self {
+        Self {
+            fileids: vec![],
+            ancestors: vec![],
+        }
+    }
+
+    pub fn get_root(&self) -> Option<&'a nodes::FileId> {
+        self.fileids.first().copied()
+    }
+
+    pub fn get_current(&self) -> Option<&'a nodes::FileId> {
+        self.fileids.last().copied()
+    }
+
+    /// The ancestors of the current node, outermost first.
+    pub fn ancestors(&self) -> &[&'a nodes::Node] {
+        &self.ancestors
+    }
+
+    pub fn parent(&self) -> Option<&'a nodes::Node> {
+        self.ancestors.last().copied()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.ancestors.len()
+    }
+
+    /// The heading of the innermost enclosing section that has one.
+    pub fn enclosing_heading(&self) -> Option<&'a nodes::Node> {
+        self.ancestors
+            .iter()
+            .rev()
+            .filter(|ancestor| matches!(ancestor.data, nodes::NodeData::Section(_)))
+            .find_map(|section| {
+                section
+                    .data
+                    .children()
+                    .iter()
+                    .find(|child| matches!(child.data, nodes::NodeData::Heading(_)))
+            })
+    }
+
+    pub(crate) fn push_fileid(&mut self, fileid: &'a nodes::FileId) {
+        self.fileids.push(fileid);
+    }
+
+    pub(crate) fn pop_fileid(&mut self) {
+        self.fileids.pop();
+    }
+
+    pub(crate) fn push_ancestor(&mut self, node: &'a nodes::Node) {
+        self.ancestors.push(node);
+    }
+
+    pub(crate) fn pop_ancestor(&mut self) {
+        self.ancestors.pop();
+    }
+}
 
-    fn enter_node(&mut self, _fileid_stack: &FileIdStack, _node: &mut nodes::Node) {}
-    fn exit_node(&mut self, _fileid_stack: &FileIdStack, _node: &mut nodes::Node) {}
+/// A read-only counterpart to [`Analyzer`]. Visitors only borrow the tree, so several may
+/// traverse the same document concurrently.
+pub trait Visitor<'a> {
+    fn enter_node(&mut self, _context: &VisitorContext<'a>, _node: &'a nodes::Node) {}
+    fn exit_node(&mut self, _context: &VisitorContext<'a>, _node: &'a nodes::Node) {}
 }
 
 pub struct SimpleAnalyzer<'a> {
@@ -71,35 +271,35 @@ impl<'a> SimpleAnalyzer<'a> {
 }
 
 impl<'a> Analyzer for SimpleAnalyzer<'a> {
-    fn enter_node(&mut self, _fileid_stack: &FileIdStack, node: &mut nodes::Node) {
+    fn enter_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) {
         (self.f)(node);
     }
 }
 
+/// Record every target in the target database. Run after [`crate::html_ids::HtmlIdPass`],
+/// which gives each target its html_id.
 pub struct TargetPass1<'a> {
     db: &'a Mutex<target_database::TargetDatabase>,
-    target_counter: HashMap<String, u32>,
 }
 
 impl<'a> TargetPass1<'a> {
     pub fn new(db: &'a Mutex<target_database::TargetDatabase>) -> Self {
-        Self {
-            db,
-            target_counter: HashMap::new(),
-        }
+        Self { db }
     }
 }
 
 impl<'a> Analyzer for TargetPass1<'a> {
-    fn enter_page(&mut self, _fileid_stack: &FileIdStack, _page: &nodes::Document) {
-        self.target_counter.clear();
-    }
-
-    fn enter_node(&mut self, fileid_stack: &FileIdStack, node: &mut nodes::Node) {
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
         if let nodes::NodeData::Target(ref mut target) = node.data {
-            // Frankly, this is silly. We just pick the longest identifier. This is arbitrary,
-            // and we can consider this behavior implementation-defined to be changed later if needed.
-            // It just needs to be something consistent.
+            let chosen_html_id = match target
+                .html_id
+                .to_owned()
+                .or_else(|| html_ids::target_base_id(target))
+            {
+                Some(html_id) => html_id,
+                None => return,
+            };
+
             let identifiers: Vec<&nodes::TargetIdentifier> = target
                 .children
                 .iter()
@@ -112,37 +312,6 @@ impl<'a> Analyzer for TargetPass1<'a> {
                 })
                 .collect();
 
-            let candidates: Vec<&String> = identifiers
-                .iter()
-                .filter_map(|node| node.ids.iter().max_by_key(|id| id.len()))
-                .collect();
-
-            let chosen_id =
-                if let Some(id) = candidates.iter().max_by_key(|candidate| candidate.len()) {
-                    id
-                } else {
-                    return;
-                };
-            let mut chosen_html_id = format!(
-                "{}-{}-{}",
-                &target.domain,
-                target.name,
-                make_html5_id(chosen_id)
-            );
-
-            // Disambiguate duplicate IDs, should they occur.
-            let counter = *self
-                .target_counter
-                .entry(chosen_html_id.to_owned())
-                .or_insert(0);
-            if counter > 0 {
-                chosen_html_id += &format!("-{}", counter);
-            }
-            self.target_counter
-                .entry(chosen_html_id.to_owned())
-                .and_modify(|c| *c += 1);
-            target.html_id = Some(chosen_html_id.to_owned());
-
             let mut db = self.db.lock().unwrap();
             for target_identifier in identifiers {
                 let title = if target_identifier.children.is_empty() {
@@ -153,13 +322,18 @@ impl<'a> Analyzer for TargetPass1<'a> {
 
                 let target_ids: Vec<&str> =
                     target_identifier.ids.iter().map(|x| x.as_ref()).collect();
+                // Targets defined in included files belong to the page including them, but
+                // remember where they were actually written.
                 db.define_local_target(
                     &target.domain,
                     &target.name,
                     &target_ids,
-                    fileid_stack
+                    context
                         .get_root()
                         .expect("Analysis started at non-root node"),
+                    context
+                        .get_current()
+                        .expect("Analysis started at non-root node"),
                     &title,
                     &chosen_html_id,
                 );
@@ -167,3 +341,193 @@ impl<'a> Analyzer for TargetPass1<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn is_target(node: &nodes::Node) -> bool {
+        matches!(node.data, nodes::NodeData::Target(_))
+    }
+
+    /// For each target, record its depth, its parent's type, and the enclosing heading.
+    #[derive(Default)]
+    struct TargetHeadings {
+        results: Vec<(usize, &'static str, String)>,
+    }
+
+    impl Analyzer for TargetHeadings {
+        fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+            if is_target(node) {
+                self.results.push((
+                    context.depth(),
+                    context.parent().unwrap().node_type,
+                    context.enclosing_heading().unwrap().id.to_owned(),
+                ));
+            }
+        }
+    }
+
+    impl<'a> Visitor<'a> for TargetHeadings {
+        fn enter_node(&mut self, context: &VisitorContext<'a>, node: &'a nodes::Node) {
+            if is_target(node) {
+                let heading = match &context.enclosing_heading().unwrap().data {
+                    nodes::NodeData::Heading(heading) => heading.id.to_owned(),
+                    _ => unreachable!(),
+                };
+                self.results.push((
+                    context.depth(),
+                    context.parent().unwrap().data.type_name(),
+                    heading,
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn enclosing_heading() {
+        let mut doc = nodes::tests::load_sample();
+
+        let mut analyzer = TargetHeadings::default();
+        doc.ast.run_analyzer(&mut analyzer);
+        assert_eq!(
+            analyzer.results,
+            vec![
+                (3, "section", "numeric-functions-and-operators".to_owned()),
+                (3, "section", "subquery-constructors".to_owned()),
+                (3, "section", "utility-statements".to_owned())
+            ]
+        );
+
+        // Visitors only borrow the tree, so several can run over one document at once
+        let (left, right) = std::thread::scope(|scope| {
+            let left = scope.spawn(|| {
+                let mut visitor = TargetHeadings::default();
+                doc.ast.visit(&mut visitor);
+                visitor.results
+            });
+            let right = scope.spawn(|| {
+                let mut visitor = TargetHeadings::default();
+                doc.ast.visit(&mut visitor);
+                visitor.results
+            });
+            (left.join().unwrap(), right.join().unwrap())
+        });
+
+        assert_eq!(left, analyzer.results);
+        assert_eq!(right, analyzer.results);
+    }
+
+    /// Record the enclosing heading title of each target.
+    struct TargetTitles {
+        needs_titles: bool,
+        titles: Vec<Option<String>>,
+    }
+
+    impl Analyzer for TargetTitles {
+        fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+            if is_target(node) {
+                let heading = context.enclosing_heading().unwrap();
+                self.titles.push(heading.title.to_owned());
+            }
+        }
+
+        fn needs_heading_titles(&self) -> bool {
+            self.needs_titles
+        }
+    }
+
+    #[test]
+    fn heading_titles() {
+        let mut doc = nodes::tests::load_sample();
+
+        let mut analyzer = TargetTitles {
+            needs_titles: false,
+            titles: vec![],
+        };
+        doc.ast.run_analyzer(&mut analyzer);
+        assert_eq!(analyzer.titles, vec![None, None, None]);
+
+        let mut analyzer = TargetTitles {
+            needs_titles: true,
+            titles: vec![],
+        };
+        doc.ast.run_analyzer(&mut analyzer);
+        assert_eq!(
+            analyzer.titles,
+            vec![
+                Some("Numeric Functions and Operators".to_owned()),
+                Some("Subquery Constructors".to_owned()),
+                Some("Utility Statements".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn headings_after_targets() {
+        // Each target precedes its section's heading
+        let target = |name: &str| {
+            bson::bson!({
+                "type": "target",
+                "position": {"start": {"line": 0}},
+                "domain": "std",
+                "name": "label",
+                "children": [],
+                "html_id": name,
+            })
+        };
+        let heading = |id: &str, title: &str| {
+            bson::bson!({
+                "type": "heading",
+                "position": {"start": {"line": 0}},
+                "id": id,
+                "children": [{"type": "text", "position": {"start": {"line": 0}}, "value": title}],
+            })
+        };
+        let mut ast: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "index.txt",
+            "children": [{
+                "type": "section",
+                "position": {"start": {"line": 0}},
+                "children": [
+                    target("introduction"),
+                    heading("introduction", "Introduction"),
+                    {
+                        "type": "section",
+                        "position": {"start": {"line": 0}},
+                        "children": [target("details"), heading("details", "Details")],
+                    },
+                ],
+            }],
+        }))
+        .unwrap();
+
+        let mut analyzer = TargetHeadings::default();
+        ast.run_analyzer(&mut analyzer);
+        assert_eq!(
+            analyzer.results,
+            vec![
+                (2, "section", "introduction".to_owned()),
+                (3, "section", "details".to_owned())
+            ]
+        );
+
+        let mut visitor = TargetHeadings::default();
+        ast.visit(&mut visitor);
+        assert_eq!(visitor.results, analyzer.results);
+
+        let mut analyzer = TargetTitles {
+            needs_titles: true,
+            titles: vec![],
+        };
+        ast.run_analyzer(&mut analyzer);
+        assert_eq!(
+            analyzer.titles,
+            vec![Some("Introduction".to_owned()), Some("Details".to_owned())]
+        );
+    }
+}
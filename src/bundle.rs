@@ -1,13 +1,29 @@
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek};
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::links;
 use crate::nodes;
+use crate::toctree;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Place a refuri that points at a page within this bundle under the given namespace. Doc
+/// paths are relative to the project root whether or not they start with a slash, so the
+/// result is always absolute; a relative one would be resolved by browsers against the
+/// current page's directory. Returns None for external links (anything with a URI scheme,
+/// such as http(s)://) and for same-page anchors, which remain valid as-is.
+fn namespace_refuri(namespace: &Path, refuri: &str) -> Option<String> {
+    if !links::is_internal_refuri(refuri) {
+        return None;
+    }
+
+    let path = refuri.trim_start_matches('/');
+    Some(format!("/{}", namespace.join(path).to_str().unwrap()))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SiteMetadata {
     project: String,
     branch: String,
@@ -21,6 +37,10 @@ impl SiteMetadata {
         }
     }
 
+    pub fn project(&self) -> &str {
+        &self.project
+    }
+
     pub fn get_namespace(&self) -> String {
         format!("{}/{}", self.project, self.branch)
     }
@@ -34,6 +54,16 @@ pub enum Severity {
     Error,
 }
 
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "INFO",
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Diagnostic {
     severity: String,
@@ -41,6 +71,28 @@ pub struct Diagnostic {
     message: String,
 }
 
+impl Diagnostic {
+    pub fn new(severity: Severity, start: i32, message: impl Into<String>) -> Self {
+        Self {
+            severity: severity.as_str().to_owned(),
+            start,
+            message: message.into(),
+        }
+    }
+
+    pub fn severity(&self) -> &str {
+        &self.severity
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn start(&self) -> i32 {
+        self.start
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Diagnostics {
     pub diagnostics: Vec<Diagnostic>,
@@ -51,6 +103,213 @@ pub struct BundleIntoIterator<'a> {
     index: usize,
 }
 
+/// Iterate over the undecoded contents of each file in a bundle.
+pub struct RawEntries<'a> {
+    bundle: &'a mut Bundle,
+    index: usize,
+}
+
+/// The undecoded contents of a file in a bundle. Reading these is cheap and must happen in
+/// order, while decoding them can be spread across threads.
+pub struct RawEntry {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+}
+
+impl RawEntry {
+    /// Decode this entry into a bundle element, or None if it is not one.
+    pub fn decode(&self) -> Option<Result<BundleElement>> {
+        decode_entry(&self.path, self.data.as_slice())
+    }
+
+    /// Migrate this entry under a new namespace without decoding it, exactly as
+    /// [`BundleElement::migrate`] would. Only the fields which migration rewrites are touched;
+    /// everything else, including any fields unknown to [`nodes::Document`], is copied
+    /// verbatim. Top-level files are left alone.
+    pub fn migrate(&mut self, namespace: &Path) -> Result<()> {
+        let path = self.path.clone();
+        let mut components = path.components();
+        let prefix = match components.next() {
+            Some(prefix) if components.clone().next().is_some() => prefix,
+            _ => return Ok(()),
+        };
+
+        if prefix.as_os_str() == "documents" {
+            let document = bson::RawDocument::from_bytes(&self.data)
+                .with_context(|| format!("Error reading document BSON: {}", self.path.display()))?;
+            self.data = migrate_raw_document(document, namespace)
+                .with_context(|| format!("Error migrating document: {}", self.path.display()))?
+                .into_bytes();
+        }
+
+        self.path = Path::new(&prefix)
+            .join(namespace)
+            .join(components.as_path());
+        Ok(())
+    }
+
+    /// Whether this entry is a document stored as BSON, which can be migrated and inspected
+    /// without decoding it.
+    pub fn is_bson_document(&self) -> bool {
+        self.path.starts_with("documents")
+            && OutputFormat::from_path(&self.path).unwrap_or_default() == OutputFormat::Bson
+    }
+
+    /// Whether any node of this document matches a predicate, given the node's type and its
+    /// raw contents. Entries which are not BSON documents have no nodes to match.
+    pub fn any_node(&self, predicate: impl Fn(&str, &bson::RawDocument) -> bool) -> Result<bool> {
+        if !self.is_bson_document() {
+            return Ok(false);
+        }
+
+        let document = bson::RawDocument::from_bytes(&self.data)
+            .with_context(|| format!("Error reading document BSON: {}", self.path.display()))?;
+        let ast = document
+            .get_document("ast")
+            .with_context(|| format!("Document has no AST: {}", self.path.display()))?;
+        any_raw_node(ast, &predicate)
+    }
+}
+
+fn any_raw_node(
+    node: &bson::RawDocument,
+    predicate: &impl Fn(&str, &bson::RawDocument) -> bool,
+) -> Result<bool> {
+    if predicate(node.get_str("type").unwrap_or_default(), node) {
+        return Ok(true);
+    }
+
+    if let Ok(children) = node.get_array("children") {
+        for child in children {
+            if let bson::RawBsonRef::Document(child) = child? {
+                if any_raw_node(child, predicate)? {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Join a path onto a namespace, as a string.
+fn namespace_path(namespace: &Path, path: &str) -> String {
+    namespace.join(path).to_str().unwrap().to_owned()
+}
+
+fn migrate_raw_document(
+    document: &bson::RawDocument,
+    namespace: &Path,
+) -> Result<bson::RawDocumentBuf> {
+    let mut result = bson::RawDocumentBuf::new();
+    for element in document {
+        let (key, value) = element?;
+        match (key, value) {
+            ("page_id", bson::RawBsonRef::String(page_id)) => {
+                result.append(key, namespace_path(namespace, page_id))
+            }
+            ("ast", bson::RawBsonRef::Document(ast)) => {
+                result.append(key, migrate_raw_node(ast, namespace)?)
+            }
+            _ => result.append_ref(key, value),
+        }
+    }
+
+    Ok(result)
+}
+
+/// The raw counterpart of the node handler in [`BundleElement::migrate`].
+fn migrate_raw_node(node: &bson::RawDocument, namespace: &Path) -> Result<bson::RawDocumentBuf> {
+    let node_type = node.get_str("type").unwrap_or_default();
+    let mut result = bson::RawDocumentBuf::new();
+    for element in node {
+        let (key, value) = element?;
+        match (node_type, key, value) {
+            (_, "children", bson::RawBsonRef::Array(children)) => {
+                let mut new_children = bson::RawArrayBuf::new();
+                for child in children {
+                    match child? {
+                        bson::RawBsonRef::Document(child) => {
+                            new_children.push(migrate_raw_node(child, namespace)?)
+                        }
+                        child => new_children.push(child.to_raw_bson()),
+                    }
+                }
+                result.append(key, new_children);
+            }
+            ("root", "fileid", bson::RawBsonRef::String(fileid)) => {
+                result.append(key, namespace_path(namespace, fileid))
+            }
+            ("ref_role", "fileid", bson::RawBsonRef::Array(fileid)) => {
+                let mut new_fileid = bson::RawArrayBuf::new();
+                for (i, part) in fileid.into_iter().enumerate() {
+                    match (i, part?) {
+                        (0, bson::RawBsonRef::String(orig_fileid)) => {
+                            new_fileid.push(namespace_path(namespace, orig_fileid))
+                        }
+                        (_, part) => new_fileid.push(part.to_raw_bson()),
+                    }
+                }
+                result.append(key, new_fileid);
+            }
+            ("reference" | "named_reference", "refuri", bson::RawBsonRef::String(refuri)) => {
+                match namespace_refuri(namespace, refuri) {
+                    Some(new_refuri) => result.append(key, new_refuri),
+                    None => result.append_ref(key, value),
+                }
+            }
+            ("directive", "entries", bson::RawBsonRef::Array(entries)) => {
+                let mut new_entries = bson::RawArrayBuf::new();
+                for entry in entries {
+                    match entry? {
+                        bson::RawBsonRef::Document(entry) => {
+                            new_entries.push(migrate_raw_toctree_entry(entry, namespace)?)
+                        }
+                        entry => new_entries.push(entry.to_raw_bson()),
+                    }
+                }
+                result.append(key, new_entries);
+            }
+            _ => result.append_ref(key, value),
+        }
+    }
+
+    Ok(result)
+}
+
+fn migrate_raw_toctree_entry(
+    entry: &bson::RawDocument,
+    namespace: &Path,
+) -> Result<bson::RawDocumentBuf> {
+    // Toctree entries pointing into other projects are grafted in when merging
+    // toctrees; only entries within this project are ours to rewrite.
+    if entry.get("ref_project")?.is_some_and(|project| {
+        !matches!(
+            project,
+            bson::RawBsonRef::Null | bson::RawBsonRef::Undefined
+        )
+    }) {
+        return Ok(entry.to_owned());
+    }
+
+    let mut result = bson::RawDocumentBuf::new();
+    for element in entry {
+        let (key, value) = element?;
+        match (key, value) {
+            ("slug", bson::RawBsonRef::String(slug)) => result.append(
+                key,
+                format!(
+                    "/{}",
+                    namespace_path(namespace, slug.trim_start_matches('/'))
+                ),
+            ),
+            _ => result.append_ref(key, value),
+        }
+    }
+
+    Ok(result)
+}
+
 pub struct BundleElement {
     pub name: PathBuf,
     pub data: BundleElementData,
@@ -75,25 +334,123 @@ impl BundleElement {
                 .unwrap()
                 .to_owned();
 
-            let mut migrate_handler = &mut |node: &mut nodes::Node| match &mut node.data {
-                nodes::NodeData::RefRole(refrole) => {
-                    if let Some((orig_fileid, html5_id)) = &mut refrole.fileid {
-                        refrole.fileid = Some((
-                            namespace.join(orig_fileid).to_str().unwrap().to_owned(),
-                            html5_id.to_owned(),
-                        ));
-                    }
-                }
-                nodes::NodeData::Root(root) => {
-                    let new_fileid = namespace.join(&root.fileid.path);
-                    root.fileid = nodes::FileId::from(new_fileid);
+            migrate_node(&mut document.ast, namespace);
+        }
+    }
+}
+
+/// Migrate the internal references within a node and its descendants to be under a new
+/// namespace. Nodes copied out of a bundle before it is migrated, such as substitution
+/// definitions, must go through this before being placed into another bundle's documents.
+pub fn migrate_node(node: &mut nodes::Node, namespace: &Path) {
+    let mut migrate_handler = &mut |node: &mut nodes::Node| match &mut node.data {
+        nodes::NodeData::RefRole(refrole) => {
+            if let Some((orig_fileid, html5_id)) = &mut refrole.fileid {
+                refrole.fileid = Some((
+                    namespace.join(orig_fileid).to_str().unwrap().to_owned(),
+                    html5_id.to_owned(),
+                ));
+            }
+        }
+        nodes::NodeData::Root(root) => {
+            let new_fileid = namespace.join(&root.fileid.path);
+            root.fileid = nodes::FileId::from(new_fileid);
+        }
+        nodes::NodeData::Reference(reference) => {
+            if let Some(new_refuri) = namespace_refuri(namespace, &reference.refuri) {
+                reference.refuri = new_refuri;
+            }
+        }
+        nodes::NodeData::NamedReference(reference) => {
+            if let Some(new_refuri) = namespace_refuri(namespace, &reference.refuri) {
+                reference.refuri = new_refuri;
+            }
+        }
+        nodes::NodeData::Directive(directive) => {
+            // Toctree entries pointing into other projects are grafted in when merging
+            // toctrees; only entries within this project are ours to rewrite.
+            for entry in directive.entries.iter_mut().flatten() {
+                if let (Some(slug), None) = (&entry.slug, &entry.ref_project) {
+                    let new_slug = namespace.join(slug.trim_start_matches('/'));
+                    entry.slug = Some(format!("/{}", new_slug.to_str().unwrap()));
                 }
-                _ => (),
-            };
+            }
+        }
+        _ => (),
+    };
+
+    node.for_each(&mut migrate_handler);
+}
+
+/// Convert a value to JSON with the keys of every object sorted.
+fn sorted_json(value: &impl Serialize) -> Result<serde_json::Value> {
+    fn sort(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => {
+                let sorted: std::collections::BTreeMap<String, serde_json::Value> = map
+                    .into_iter()
+                    .map(|(key, value)| (key, sort(value)))
+                    .collect();
+                serde_json::Value::Object(sorted.into_iter().collect())
+            }
+            serde_json::Value::Array(values) => {
+                serde_json::Value::Array(values.into_iter().map(sort).collect())
+            }
+            value => value,
+        }
+    }
+
+    Ok(sort(serde_json::to_value(value)?))
+}
 
-            document.ast.for_each(&mut migrate_handler);
+/// How structured entries (documents, diagnostics and metadata) are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Bson,
+
+    /// JSON with object keys sorted, so that identical documents are written identically
+    Json,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Bson => "bson",
+            OutputFormat::Json => "json",
+        }
+    }
+
+    /// The format of a bundle entry, judged by its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "bson" => Some(OutputFormat::Bson),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
         }
     }
+
+    pub fn decode<T: serde::de::DeserializeOwned>(self, reader: impl Read) -> Result<T> {
+        Ok(match self {
+            OutputFormat::Bson => bson::from_reader(reader)?,
+            OutputFormat::Json => serde_json::from_reader(reader)?,
+        })
+    }
+
+    pub fn encode(self, value: &impl Serialize) -> Result<Vec<u8>> {
+        Ok(match self {
+            OutputFormat::Bson => bson::to_vec(value)?,
+            OutputFormat::Json => serde_json::to_vec(&sorted_json(value)?)?,
+        })
+    }
+
+    /// Like [`OutputFormat::encode`], but indenting JSON for people to read.
+    pub fn encode_pretty(self, value: &impl Serialize) -> Result<Vec<u8>> {
+        Ok(match self {
+            OutputFormat::Bson => bson::to_vec(value)?,
+            OutputFormat::Json => serde_json::to_vec_pretty(&sorted_json(value)?)?,
+        })
+    }
 }
 
 pub enum BundleElementData {
@@ -112,9 +469,176 @@ impl BundleElementData {
     }
 }
 
+trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Where a bundle's entries are read from.
+enum Source {
+    Zip(zip::ZipArchive<Box<dyn ReadSeek>>),
+    /// An unpacked bundle, with each entry's path relative to the root directory.
+    Directory {
+        root: PathBuf,
+        entries: Vec<PathBuf>,
+    },
+    /// A tar archive. These can only be read front to back, so every entry is held in memory.
+    Memory(Vec<(PathBuf, Vec<u8>)>),
+}
+
+impl Source {
+    /// Open a zip, tar or gzipped tar archive, recognized by its leading bytes.
+    fn from_reader(mut reader: impl Read + Seek + Send + 'static) -> Result<Self> {
+        let mut header = vec![];
+        (&mut reader).take(262).read_to_end(&mut header)?;
+        reader.rewind()?;
+
+        if header.starts_with(b"PK") {
+            let reader: Box<dyn ReadSeek> = Box::new(reader);
+            Ok(Source::Zip(zip::ZipArchive::new(reader)?))
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            read_tar(flate2::read::GzDecoder::new(reader))
+        } else if header.get(257..262) == Some(b"ustar") {
+            read_tar(reader)
+        } else {
+            anyhow::bail!("Unrecognized bundle format: expected a zip, tar or tar.gz archive")
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Source::Zip(archive) => archive.len(),
+            Source::Directory { entries, .. } => entries.len(),
+            Source::Memory(entries) => entries.len(),
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        match self {
+            Source::Zip(archive) => archive.index_for_name(name).is_some(),
+            Source::Directory { entries, .. } => entries.iter().any(|path| path == Path::new(name)),
+            Source::Memory(entries) => entries.iter().any(|(path, _)| path == Path::new(name)),
+        }
+    }
+
+    fn by_name(&mut self, name: &str) -> Result<Box<dyn Read + '_>> {
+        match self {
+            Source::Zip(archive) => Ok(Box::new(archive.by_name(name)?)),
+            Source::Directory { root, .. } => {
+                if !Path::new(name)
+                    .components()
+                    .all(|component| matches!(component, std::path::Component::Normal(_)))
+                {
+                    anyhow::bail!("Bundle entry {} has a prohibited path", name);
+                }
+
+                Ok(Box::new(BufReader::new(File::open(root.join(name))?)))
+            }
+            Source::Memory(entries) => match entries.iter().find(|(path, _)| path == name) {
+                Some((_, data)) => Ok(Box::new(data.as_slice())),
+                None => anyhow::bail!("No such bundle entry: {}", name),
+            },
+        }
+    }
+
+    /// Open the entry at the given index, returning its path within the bundle. Returns None
+    /// for entries which should be skipped, such as directories.
+    fn by_index(&mut self, idx: usize) -> Result<Option<(PathBuf, Box<dyn Read + '_>)>> {
+        match self {
+            Source::Zip(archive) => {
+                let file = archive.by_index(idx)?;
+                if !file.is_file() {
+                    return Ok(None);
+                }
+
+                match file.enclosed_name() {
+                    Some(path) => Ok(Some((path, Box::new(file)))),
+                    None => {
+                        log::warn!("Bundle entry {} has a prohibited path", file.name());
+                        Ok(None)
+                    }
+                }
+            }
+            Source::Directory { root, entries } => {
+                let path = &entries[idx];
+                let file = File::open(root.join(path))
+                    .with_context(|| format!("Error opening bundle entry: {}", path.display()))?;
+                Ok(Some((path.to_owned(), Box::new(BufReader::new(file)))))
+            }
+            Source::Memory(entries) => {
+                let (path, data) = &entries[idx];
+                Ok(Some((path.to_owned(), Box::new(data.as_slice()))))
+            }
+        }
+    }
+}
+
+/// Read every file in a tar archive into memory.
+fn read_tar(reader: impl Read) -> Result<Source> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = vec![];
+    for entry in archive.entries().context("Error reading tar bundle")? {
+        let mut entry = entry.context("Error reading tar bundle")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        // Archives are often created with paths like "./documents/index.bson"
+        let raw_path = entry.path()?.into_owned();
+        let mut path = PathBuf::new();
+        for component in raw_path.components() {
+            match component {
+                std::path::Component::Normal(part) => path.push(part),
+                std::path::Component::CurDir => (),
+                _ => {
+                    path.clear();
+                    break;
+                }
+            }
+        }
+
+        if path.as_os_str().is_empty() {
+            log::warn!("Bundle entry {} has a prohibited path", raw_path.display());
+            continue;
+        }
+
+        let mut data = vec![];
+        entry
+            .read_to_end(&mut data)
+            .with_context(|| format!("Error reading bundle entry: {}", path.display()))?;
+        entries.push((path, data));
+    }
+
+    Ok(Source::Memory(entries))
+}
+
+/// List every file beneath a directory, relative to it, in sorted order.
+fn list_directory(root: &Path) -> Result<Vec<PathBuf>> {
+    fn walk(root: &Path, relative: &Path, entries: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in std::fs::read_dir(root.join(relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                walk(root, &path, entries)?;
+            } else {
+                entries.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut entries = vec![];
+    walk(root, Path::new(""), &mut entries)
+        .with_context(|| format!("Error reading bundle directory: {}", root.display()))?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// A Snooty bundle, read from a zip, tar or gzipped tar archive, or from an unpacked
+/// directory with the same layout.
 pub struct Bundle {
     pub metadata: SiteMetadata,
-    archive: zip::ZipArchive<BufReader<File>>,
+    source: Source,
 }
 
 impl<'a> IntoIterator for &'a mut Bundle {
@@ -130,14 +654,155 @@ impl<'a> IntoIterator for &'a mut Bundle {
 }
 
 impl Bundle {
+    /// Open the bundle at a path, or read it from stdin if the path is "-".
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let file = std::fs::File::open(path).unwrap();
-        let reader = std::io::BufReader::new(file);
-        let mut archive = zip::ZipArchive::new(reader).unwrap();
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            return Self::from_reader(std::io::stdin().lock())
+                .context("Error reading bundle from stdin");
+        }
+
+        let source = if path.is_dir() {
+            Source::Directory {
+                root: path.to_owned(),
+                entries: list_directory(path)?,
+            }
+        } else {
+            let file = File::open(path)
+                .with_context(|| format!("Error opening bundle: {}", path.display()))?;
+            Source::from_reader(BufReader::new(file))
+                .with_context(|| format!("Error reading bundle: {}", path.display()))?
+        };
+
+        Self::from_source(source)
+            .with_context(|| format!("Error reading site metadata from {}", path.display()))
+    }
+
+    /// Read an archived bundle from a stream, such as stdin. The whole stream is buffered in
+    /// memory, since archives cannot be read without seeking.
+    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Self::from_source(Source::from_reader(std::io::Cursor::new(data))?)
+    }
+
+    /// Read a bundle's metadata from site.bson, or from site.json if it was written as JSON.
+    fn from_source(mut source: Source) -> Result<Self> {
+        let format = [OutputFormat::Bson, OutputFormat::Json]
+            .into_iter()
+            .find(|format| source.contains(&format!("site.{}", format.extension())))
+            .context("Bundle has no site.bson or site.json")?;
+        let metadata = format.decode(source.by_name(&format!("site.{}", format.extension()))?)?;
+        Ok(Bundle { metadata, source })
+    }
+
+    /// The number of entries in this bundle, including any which are not bundle elements.
+    pub fn n_entries(&self) -> usize {
+        self.source.len()
+    }
+
+    /// Iterate over the files of this bundle without decoding them.
+    pub fn raw_entries(&mut self) -> RawEntries<'_> {
+        RawEntries {
+            bundle: self,
+            index: 0,
+        }
+    }
+
+    /// Convert a single entry of this bundle, such as "documents/index.bson" or "site.bson",
+    /// to the given format. Entries may be stored as either BSON or JSON.
+    pub fn dump_entry(&mut self, name: &str, format: OutputFormat) -> Result<Vec<u8>> {
+        let file = self
+            .source
+            .by_name(name)
+            .with_context(|| format!("No such bundle entry: {}", name))?;
+
+        let stored = OutputFormat::from_path(Path::new(name)).unwrap_or_default();
+        match name {
+            "site.bson" | "site.json" => {
+                format.encode_pretty(&stored.decode::<SiteMetadata>(file)?)
+            }
+            "toctree.bson" | "toctree.json" => {
+                format.encode_pretty(&stored.decode::<toctree::TocTreeNode>(file)?)
+            }
+            _ => match read_element(Path::new(name), file) {
+                Some(element) => match element?.data {
+                    BundleElementData::Document(document) => format.encode_pretty(&document),
+                    BundleElementData::Diagnostics(diagnostics) => {
+                        format.encode_pretty(&Diagnostics { diagnostics })
+                    }
+                    BundleElementData::Asset(_) => {
+                        anyhow::bail!("Cannot dump asset {}: assets are not BSON", name)
+                    }
+                },
+                None => anyhow::bail!("Unrecognized bundle entry: {}", name),
+            },
+        }
+    }
+}
+
+/// Decode an archive entry into a bundle element, warning about any entry that does not
+/// belong in a bundle.
+fn decode_entry(filename: &Path, file: impl Read) -> Option<Result<BundleElement>> {
+    match read_element(filename, file) {
+        Some(element) => Some(element),
+        // Top-level files hold site-wide data, such as site.bson or the indexes
+        // written alongside a stitched output
+        None if filename.components().count() == 1 => None,
+        None => {
+            log::warn!("Unexpected bundle entry: {}", filename.display());
+            None
+        }
+    }
+}
+
+/// Decode an archive entry into a bundle element. Returns None if the entry is not part of
+/// the documents, assets or diagnostics trees. Documents and diagnostics are decoded as JSON
+/// if their extension says so, and as BSON otherwise.
+fn read_element(filename: &Path, mut file: impl Read) -> Option<Result<BundleElement>> {
+    // Split our filename into the prefix and the remainder; e.g. "documents" and "foo/bar.bson"
+    let mut components_iter = filename.components();
+    let first_component = components_iter.next()?;
+    let filename_prefix: &Path = first_component.as_ref();
+    let filename_without_prefix: PathBuf = components_iter.collect();
 
-        let metadata = bson::from_reader(archive.by_name("site.bson")?)?;
+    let format = OutputFormat::from_path(filename).unwrap_or_default();
+    if filename_prefix == Path::new("documents") {
+        Some(
+            format
+                .decode(file)
+                .with_context(|| format!("Error deserializing document: {}", filename.display()))
+                .map(|value| {
+                    BundleElement::new(filename_without_prefix, BundleElementData::Document(value))
+                }),
+        )
+    } else if filename_prefix == Path::new("assets") {
+        let mut buf: Vec<u8> = vec![];
+        if let Err(err) = file
+            .read_to_end(&mut buf)
+            .with_context(|| format!("Error reading asset: {}", filename.display()))
+        {
+            return Some(Err(err));
+        }
 
-        Ok(Bundle { metadata, archive })
+        Some(Ok(BundleElement::new(
+            filename_without_prefix,
+            BundleElementData::Asset(buf),
+        )))
+    } else if filename_prefix == Path::new("diagnostics") {
+        Some(
+            format
+                .decode(file)
+                .with_context(|| format!("Error deserializing diagnostics: {}", filename.display()))
+                .map(|value: Diagnostics| {
+                    BundleElement::new(
+                        filename_without_prefix,
+                        BundleElementData::Diagnostics(value.diagnostics),
+                    )
+                }),
+        )
+    } else {
+        None
     }
 }
 
@@ -148,78 +813,50 @@ impl<'a> Iterator for BundleIntoIterator<'a> {
         loop {
             let idx = self.index;
 
-            if idx >= self.bundle.archive.len() {
+            if idx >= self.bundle.source.len() {
                 return None;
             }
 
             self.index += 1;
 
-            let mut file = self.bundle.archive.by_index(idx).unwrap();
-            let filename = match file.enclosed_name() {
-                Some(path) => path,
-                None => {
-                    log::warn!("Bundle entry {} has a prohibited path", file.name());
-                    continue;
-                }
+            let (filename, file) = match self.bundle.source.by_index(idx) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
             };
 
-            if !file.is_file() {
-                continue;
+            if let Some(element) = decode_entry(&filename, file) {
+                return Some(element);
             }
+        }
+    }
+}
 
-            // Split our filename into the prefix and the remainder; e.g. "documents" and "foo/bar.bson"
-            let mut components_iter = filename.components();
-            let first_component = components_iter.next().unwrap();
-            let filename_prefix: &Path = first_component.as_ref();
-            let filename_without_prefix: PathBuf = components_iter.collect();
-
-            if filename_prefix == Path::new("documents") {
-                return Some(
-                    bson::from_reader(file)
-                        .with_context(|| {
-                            format!("Error deserializing document BSON: {}", filename.display())
-                        })
-                        .map(|value| {
-                            BundleElement::new(
-                                filename_without_prefix,
-                                BundleElementData::Document(value),
-                            )
-                        }),
-                );
-            } else if filename_prefix == Path::new("assets") {
-                let mut buf: Vec<u8> = vec![];
-                if let Err(err) = file
-                    .read_to_end(&mut buf)
-                    .with_context(|| format!("Error reading asset: {}", filename.display()))
-                {
-                    return Some(Err(err));
-                }
+impl<'a> Iterator for RawEntries<'a> {
+    type Item = anyhow::Result<RawEntry>;
 
-                return Some(Ok(BundleElement::new(
-                    filename_without_prefix,
-                    BundleElementData::Asset(buf),
-                )));
-            } else if filename_prefix == Path::new("diagnostics") {
-                return Some(
-                    bson::from_reader(file)
-                        .with_context(|| {
-                            format!(
-                                "Error deserializing diagnostic BSON: {}",
-                                filename.display()
-                            )
-                        })
-                        .map(|value: Diagnostics| {
-                            BundleElement::new(
-                                filename_without_prefix,
-                                BundleElementData::Diagnostics(value.diagnostics),
-                            )
-                        }),
-                );
-            } else if filename == Path::new("site.bson") {
-                continue;
-            } else {
-                log::warn!("Unexpected bundle entry: {}", filename.display());
+    fn next(&mut self) -> Option<anyhow::Result<RawEntry>> {
+        loop {
+            let idx = self.index;
+
+            if idx >= self.bundle.source.len() {
+                return None;
             }
+
+            self.index += 1;
+
+            let (path, mut file) = match self.bundle.source.by_index(idx) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            };
+
+            let mut data = vec![];
+            return Some(
+                file.read_to_end(&mut data)
+                    .with_context(|| format!("Error reading bundle entry: {}", path.display()))
+                    .map(|_| RawEntry { path, data }),
+            );
         }
     }
 }
@@ -298,4 +935,276 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn migrate_refuri() {
+        let mut element = BundleElement::new(
+            PathBuf::from("index.bson"),
+            BundleElementData::Document(Box::new(
+                bson::from_bson(bson::bson![
+                    {"page_id": "index",
+                    "filename": "index.txt",
+                    "ast": {
+                        "type": "root",
+                        "position": {"start": {"line": 0}},
+                        "children": [
+                            {
+                                "type": "reference",
+                                "position": {"start": {"line": 0}},
+                                "children": [],
+                                "refuri": "/reference/operator"
+                            },
+                            {
+                                "type": "reference",
+                                "position": {"start": {"line": 0}},
+                                "children": [],
+                                "refuri": "tutorial/install#linux"
+                            },
+                            {
+                                "type": "reference",
+                                "position": {"start": {"line": 0}},
+                                "children": [],
+                                "refuri": "https://www.mongodb.com/docs/manual/aggregation/"
+                            },
+                            {
+                                "type": "reference",
+                                "position": {"start": {"line": 0}},
+                                "children": [],
+                                "refuri": "#same-page"
+                            },
+                            {
+                                "type": "named_reference",
+                                "position": {"start": {"line": 0}},
+                                "refname": "faq",
+                                "refuri": "faq"
+                            },
+                            {
+                                "type": "named_reference",
+                                "position": {"start": {"line": 0}},
+                                "refname": "support",
+                                "refuri": "mailto:support@example.com"
+                            }
+                        ],
+                        "fileid": "index.txt"
+                    },
+                    "source": "",
+                    "static_assets": []}
+                ])
+                .unwrap(),
+            )),
+        );
+
+        element.migrate(Path::new("migrated/main"));
+        let mut doc = if let BundleElementData::Document(doc) = element.data {
+            doc
+        } else {
+            unreachable!();
+        };
+
+        let mut refuris: Vec<String> = vec![];
+        doc.ast
+            .for_each(&mut |node: &mut nodes::Node| match &node.data {
+                NodeData::Reference(reference) => refuris.push(reference.refuri.to_owned()),
+                NodeData::NamedReference(reference) => refuris.push(reference.refuri.to_owned()),
+                _ => (),
+            });
+
+        assert_eq!(
+            refuris,
+            vec![
+                "/migrated/main/reference/operator",
+                "/migrated/main/tutorial/install#linux",
+                "https://www.mongodb.com/docs/manual/aggregation/",
+                "#same-page",
+                "/migrated/main/faq",
+                "mailto:support@example.com"
+            ]
+        );
+    }
+
+    /// Migrating raw bytes gives the same document as migrating a decoded one, while keeping
+    /// fields the decoded document would drop.
+    #[test]
+    fn migrate_raw() {
+        let document = bson::doc! {
+            "page_id": "index",
+            "filename": "index.txt",
+            "ast": {
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "children": [
+                    {
+                        "type": "ref_role",
+                        "position": {"start": {"line": 0}},
+                        "children": [],
+                        "domain": "std",
+                        "name": "label",
+                        "target": "install",
+                        "flag": "",
+                        "fileid": ["tutorial/install", "std-label-install"]
+                    },
+                    {
+                        "type": "reference",
+                        "position": {"start": {"line": 0}},
+                        "children": [],
+                        "refuri": "/reference/operator",
+                        "unknown_field": true
+                    },
+                    {
+                        "type": "named_reference",
+                        "position": {"start": {"line": 0}},
+                        "refname": "faq",
+                        "refuri": "faq#install"
+                    },
+                    {
+                        "type": "directive",
+                        "position": {"start": {"line": 0}},
+                        "children": [],
+                        "domain": "",
+                        "name": "toctree",
+                        "argument": [],
+                        "entries": [
+                            {"slug": "/tutorial/install"},
+                            {"title": "Charts", "slug": "/", "ref_project": "charts"}
+                        ]
+                    }
+                ],
+                "fileid": "index.txt"
+            },
+            "source": "",
+            "static_assets": []
+        };
+        let namespace = Path::new("atlas/main");
+        let migrate_both = |data: Vec<u8>| {
+            let mut raw = RawEntry {
+                path: PathBuf::from("documents/index.bson"),
+                data: data.clone(),
+            };
+            raw.migrate(namespace).unwrap();
+            assert_eq!(raw.path, Path::new("documents/atlas/main/index.bson"));
+
+            let mut decoded = decode_entry(Path::new("documents/index.bson"), data.as_slice())
+                .unwrap()
+                .unwrap();
+            decoded.migrate(namespace);
+
+            let encode = |element: BundleElement| match element.data {
+                BundleElementData::Document(doc) => OutputFormat::Json.encode(&doc).unwrap(),
+                _ => unreachable!(),
+            };
+            assert_eq!(
+                String::from_utf8(encode(raw.decode().unwrap().unwrap())).unwrap(),
+                String::from_utf8(encode(decoded)).unwrap()
+            );
+            raw
+        };
+
+        migrate_both(std::fs::read("test_data/supported-operations.bson").unwrap());
+        let raw = migrate_both(bson::to_vec(&document).unwrap());
+
+        let migrated: bson::Document = bson::from_slice(&raw.data).unwrap();
+        let children = migrated
+            .get_document("ast")
+            .unwrap()
+            .get_array("children")
+            .unwrap();
+        assert_eq!(
+            children[1].as_document().unwrap().get_bool("unknown_field"),
+            Ok(true)
+        );
+        assert_eq!(
+            children[2].as_document().unwrap().get_str("refuri"),
+            Ok("/atlas/main/faq#install")
+        );
+    }
+
+    #[test]
+    fn encode_json() {
+        let doc = nodes::tests::load_sample();
+
+        let encoded = OutputFormat::Json.encode(&doc).unwrap();
+        assert_eq!(encoded, OutputFormat::Json.encode(&doc).unwrap());
+
+        // Keys are sorted at every level, and the document survives a round-trip
+        let text = String::from_utf8(encoded).unwrap();
+        assert!(text.starts_with("{\"ast\":{\"children\":[{\"children\":"));
+        let decoded: nodes::Document = serde_json::from_str(&text).unwrap();
+        assert_eq!(decoded.page_id, doc.page_id);
+        assert_eq!(decoded.ast.get_text(), doc.ast.get_text());
+    }
+
+    /// Bundles with the same layout yield the same elements, whatever their format.
+    #[test]
+    fn open_formats() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let files: Vec<(&str, Vec<u8>)> = vec![
+            (
+                "site.bson",
+                bson::to_vec(&SiteMetadata::new("atlas", "main")).unwrap(),
+            ),
+            (
+                "documents/supported-operations.bson",
+                std::fs::read("test_data/supported-operations.bson").unwrap(),
+            ),
+            ("assets/abc123", b"asset".to_vec()),
+        ];
+
+        let directory = root.join("bundle");
+        let zip_path = root.join("bundle.zip");
+        let tar_path = root.join("bundle.tar");
+        let tar_gz_path = root.join("bundle.tgz");
+        let mut archive = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let mut tar_builder = tar::Builder::new(vec![]);
+        for (name, contents) in &files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            tar_builder
+                .append_data(&mut header, format!("./{name}"), contents.as_slice())
+                .unwrap();
+
+            let path = directory.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+
+            archive
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut archive, contents).unwrap();
+        }
+        archive.finish().unwrap();
+
+        let tar_data = tar_builder.into_inner().unwrap();
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&tar_gz_path).unwrap(), Default::default());
+        std::io::Write::write_all(&mut encoder, &tar_data).unwrap();
+        encoder.finish().unwrap();
+        std::fs::write(&tar_path, tar_data).unwrap();
+
+        let names = |mut bundle: Bundle| {
+            assert_eq!(bundle.metadata.project(), "atlas");
+            let mut names: Vec<PathBuf> = (&mut bundle)
+                .into_iter()
+                .map(|element| element.unwrap().get_full_bundle_path())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let expected = vec![
+            PathBuf::from("assets/abc123"),
+            PathBuf::from("documents/supported-operations.bson"),
+        ];
+        assert_eq!(names(Bundle::open(&directory).unwrap()), expected);
+        for path in [&zip_path, &tar_path, &tar_gz_path] {
+            assert_eq!(names(Bundle::open(path).unwrap()), expected);
+
+            // Archives piped through stdin are read the same way
+            let stream = File::open(path).unwrap();
+            assert_eq!(names(Bundle::from_reader(stream).unwrap()), expected);
+        }
+        assert!(Bundle::from_reader(&b"not a bundle"[..]).is_err());
+        assert!(Bundle::open(root.join("bundle/site.bson")).is_err());
+    }
 }
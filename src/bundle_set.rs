@@ -1,141 +1,1161 @@
 use std::{
-    collections::HashSet,
-    fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
 };
 
+use anyhow::Context;
+
 use crate::analyzer;
 use crate::bundle;
+use crate::config;
+use crate::facets;
+use crate::html_ids;
+use crate::links;
+use crate::output;
+use crate::passes;
+use crate::plugin;
+use crate::redirects;
+use crate::search;
+use crate::sitemap;
+use crate::substitutions;
+use crate::summary;
 use crate::target_database;
+use crate::toctree;
+use crate::transforms;
+
+/// Sent from the splice workers to the writer thread.
+enum Packet {
+    /// A document which has already been serialized, to be written at the given path.
+    Document {
+        path: String,
+        data: Vec<u8>,
+    },
+    Asset {
+        name: PathBuf,
+        data: Vec<u8>,
+    },
+    Diagnostics {
+        name: PathBuf,
+        diagnostics: Vec<bundle::Diagnostic>,
+    },
+}
+
+/// Runs a bundle's documents through its own instances of the configured plugins, one at a
+/// time and in the order in which they were read, while splice processes everything else in
+/// parallel. Every entry of the bundle must take its turn exactly once, whether or not it is a
+/// document, or the entries after it will wait forever.
+struct PluginStage {
+    state: Mutex<PluginStageState>,
+    turn: Condvar,
+}
+
+struct PluginStageState {
+    /// The position within its bundle of the entry whose turn it is
+    next: usize,
+    plugins: Vec<plugin::Plugin>,
+}
+
+impl PluginStage {
+    fn new(configs: &[plugin::PluginConfig]) -> Self {
+        Self {
+            state: Mutex::new(PluginStageState {
+                next: 0,
+                plugins: configs.iter().map(plugin::Plugin::new).collect(),
+            }),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// Wait for the turn of the entry at the given position, give the plugins to `f`, and pass
+    /// the turn on.
+    fn take_turn<R>(&self, index: usize, f: impl FnOnce(&mut [plugin::Plugin]) -> R) -> R {
+        let mut state = self
+            .turn
+            .wait_while(self.state.lock().unwrap(), |state| state.next != index)
+            .unwrap();
+        let result = f(&mut state.plugins);
+        state.next += 1;
+        self.turn.notify_all();
+        result
+    }
+}
 
 pub struct BundleSet {
     pub bundles: Vec<Mutex<bundle::Bundle>>,
+    config: config::Config,
+    db: target_database::TargetDatabase,
+    toctrees: Mutex<toctree::TocTreeDatabase>,
+    substitutions: substitutions::SubstitutionTable,
+    link_report: Mutex<links::LinkReport>,
+    summary: Mutex<summary::Summary>,
 }
 
 impl BundleSet {
-    pub fn new(bundles: impl Iterator<Item = bundle::Bundle>) -> Self {
+    pub fn new(bundles: impl Iterator<Item = bundle::Bundle>, config: config::Config) -> Self {
         Self {
             bundles: bundles.map(Mutex::new).collect(),
+            substitutions: substitutions::SubstitutionTable::from_text(&config.substitutions),
+            config,
+            db: target_database::TargetDatabase::new(),
+            toctrees: Mutex::new(toctree::TocTreeDatabase::new()),
+            link_report: Mutex::new(links::LinkReport::default()),
+            summary: Mutex::new(summary::Summary::default()),
         }
     }
-    pub fn splice(
+
+    /// Take the broken internal links found by [`BundleSet::splice`].
+    pub fn take_link_report(&self) -> links::LinkReport {
+        std::mem::take(&mut self.link_report.lock().unwrap())
+    }
+
+    /// Take the counts gathered by [`BundleSet::link`] and [`BundleSet::splice`]. Phase
+    /// timings are left for the caller to fill in.
+    pub fn take_summary(&self) -> summary::Summary {
+        std::mem::take(&mut self.summary.lock().unwrap())
+    }
+
+    /// Merge the toctrees collected by [`BundleSet::link`] into a single tree rooted at the
+    /// given umbrella project or namespace.
+    pub fn merge_toctrees(&self, umbrella: &str) -> anyhow::Result<toctree::TocTreeNode> {
+        self.toctrees.lock().unwrap().merge(umbrella)
+    }
+
+    /// Migrate, process and write the elements of every bundle into a single output bundle.
+    /// Documents are processed in parallel, so the order of entries within the output is not
+    /// deterministic, although their contents are. Plugins are the exception: each bundle's
+    /// documents pass through its plugins one at a time and in order, as plugins expect.
+    pub fn splice<S: output::OutputSink + 'static>(
         &self,
         site_metadata: &bundle::SiteMetadata,
-        mut out_bundle: zip::ZipWriter<BufWriter<File>>,
+        toctree: Option<&toctree::TocTreeNode>,
+        previous_pages: Option<&[redirects::PageInfo]>,
+        format: bundle::OutputFormat,
+        mut out_bundle: S,
     ) -> anyhow::Result<()> {
-        let options = zip::write::SimpleFileOptions::default()
-            .compression_method(zip::CompressionMethod::Stored);
+        // The summary describes the most recent splice, so start counting refs afresh
+        {
+            let mut summary = self.summary.lock().unwrap();
+            summary.refs_resolved = 0;
+            summary.refs_unresolved = 0;
+        }
+
+        let extension = format.extension();
+        out_bundle.start_file(&format!("site.{extension}"))?;
+        out_bundle.write_all(&format.encode(&site_metadata)?)?;
 
-        out_bundle.start_file("site.bson", options)?;
-        out_bundle.write_all(&bson::to_vec(&site_metadata)?)?;
+        if let Some(toctree) = toctree {
+            out_bundle.start_file(&format!("toctree.{extension}"))?;
+            out_bundle.write_all(&format.encode(toctree)?)?;
+        }
 
         // Avoid writing any asset more than once, so store the unique hash of each and skip dups
         let stored_assets: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
 
-        let (tx, rx) = crossbeam_channel::bounded::<Option<bundle::BundleElement>>(10);
+        let (tx, rx) = crossbeam_channel::bounded::<Option<Packet>>(10);
 
-        let n_cpus = std::thread::available_parallelism()?.get();
-        assert!(n_cpus >= 1_usize);
-        log::debug!("Splicing with {} threads", n_cpus);
-        let mut pool = scoped_threadpool::Pool::new(u32::try_from(n_cpus)?);
+        // The writer thread hands the archive back once every element has been written, so
+        // that outputs gathered across all bundles can be added last.
+        let thread = std::thread::spawn(move || -> anyhow::Result<(S, summary::Summary)> {
+            // Diagnostics for a file may arrive from both its bundle and from plugins, so
+            // gather them all before writing.
+            let mut pending_diagnostics: BTreeMap<PathBuf, Vec<bundle::Diagnostic>> =
+                BTreeMap::new();
+            let mut written = summary::Summary::default();
 
-        let thread = std::thread::spawn(move || -> anyhow::Result<()> {
             loop {
                 let packet = rx.recv().unwrap();
 
                 match packet {
-                    Some(element) => {
+                    Some(Packet::Document { path, data }) => {
+                        out_bundle.start_file(&path)?;
+                        out_bundle.write_all(&data)?;
+                        written.documents += 1;
+                    }
+                    Some(Packet::Asset { name, data }) => {
                         // If this asset has already been stored, skip it
-                        if let bundle::BundleElementData::Asset(asset) = &element.data {
-                            let asset_hash = element.name.file_name().ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Bundle element is missing a filename: ${:?}",
-                                    element.name
-                                )
-                            })?;
-                            let asset_hash_string = asset_hash.to_str().unwrap();
-                            let mut guard = stored_assets.lock().unwrap();
-                            if !guard.insert(asset_hash_string.to_owned()) {
-                                // This asset was already stored
-                                continue;
-                            }
-
-                            out_bundle
-                                .start_file(format!("assets/{asset_hash_string}"), options)?;
-                            out_bundle.write_all(asset)?;
+                        let asset_hash = name.file_name().ok_or_else(|| {
+                            anyhow::anyhow!("Bundle element is missing a filename: ${:?}", name)
+                        })?;
+                        let asset_hash_string = asset_hash.to_str().unwrap();
+                        let mut guard = stored_assets.lock().unwrap();
+                        if !guard.insert(asset_hash_string.to_owned()) {
+                            // This asset was already stored
+                            written.assets_deduplicated += 1;
                             continue;
                         }
 
-                        let full_path = element.get_full_bundle_path();
-                        let full_path_string = full_path.to_str().unwrap_or_else(|| {
-                            panic!("Failed to convert entry name to string: {:?}", full_path)
-                        });
-                        out_bundle.start_file(full_path_string, options).unwrap();
-
-                        match element.data {
-                            bundle::BundleElementData::Document(document) => {
-                                let serialized = bson::to_vec(&document)?;
-                                out_bundle.write_all(&serialized)?;
-                            }
-                            bundle::BundleElementData::Diagnostics(diagnostics) => {
-                                let serialized =
-                                    bson::to_vec(&bundle::Diagnostics { diagnostics })?;
-                                out_bundle.write_all(&serialized)?;
-                            }
-                            bundle::BundleElementData::Asset(_) => (), // Already written
-                        }
+                        out_bundle.start_file(&format!("assets/{asset_hash_string}"))?;
+                        out_bundle.write_all(&data)?;
+                        written.assets_written += 1;
+                    }
+                    Some(Packet::Diagnostics { name, diagnostics }) => {
+                        pending_diagnostics
+                            .entry(name)
+                            .or_default()
+                            .extend(diagnostics);
                     }
                     None => {
-                        out_bundle.finish().unwrap();
-                        return Ok(());
+                        for (name, diagnostics) in pending_diagnostics {
+                            written.diagnostics += diagnostics.len();
+                            let full_path = Path::new("diagnostics")
+                                .join(name)
+                                .with_extension(extension);
+                            out_bundle.start_file(full_path.to_str().unwrap())?;
+                            let serialized = format.encode(&bundle::Diagnostics { diagnostics })?;
+                            out_bundle.write_all(&serialized)?;
+                        }
+
+                        return Ok((out_bundle, written));
                     }
                 }
             }
         });
 
+        let search_records = Mutex::new(vec![]);
+        let facet_index = Mutex::new(facets::FacetIndex::new());
+        let sitemap_pages = Mutex::new(vec![]);
+        let pages = Mutex::new(vec![]);
+
+        let plugin_stages = self.plugin_stages();
+
+        // Documents are only decoded if something needs their AST. Outputs gathered from every
+        // page need them all; otherwise only those holding nodes which the transforms and
+        // passes act on.
+        let verbatim = format == bundle::OutputFormat::Bson
+            && plugin_stages.is_empty()
+            && !self.config.search_index
+            && !self.config.facet_index
+            && self.config.facet_taxonomy.is_empty()
+            && self.config.sitemap_base_url.is_none()
+            && previous_pages.is_none();
+
+        let processed = self.process_entries(
+            "Splicing",
+            |_| true,
+            |metadata| {
+                let mut passes = self.splice_passes(metadata);
+                passes.schedule()?;
+                Ok(passes)
+            },
+            |passes, bundle_index, metadata, index, mut entry| {
+                let namespace = metadata.get_namespace();
+
+                // Patch the migrated fields into the raw BSON and write it as it is, which
+                // is faster than decoding and encoding it again
+                if verbatim
+                    && entry.is_bson_document()
+                    && !entry.any_node(|node_type, node| self.needs_decoding(node_type, node))?
+                {
+                    entry.migrate(Path::new(&namespace))?;
+                    let path = entry.path.to_str().with_context(|| {
+                        format!("Bundle entry name is not UTF-8: {:?}", entry.path)
+                    })?;
+                    tx.send(Some(Packet::Document {
+                        path: path.to_owned(),
+                        data: entry.data,
+                    }))?;
+                    return Ok(());
+                }
+
+                // Plugins run before the passes, so that links they add are rewritten and
+                // checked like any other
+                let mut element = entry.decode().transpose();
+                let mut diagnostics = self.prepare_element(
+                    &mut element,
+                    &namespace,
+                    plugin_stages.get(bundle_index),
+                    index,
+                );
+
+                let mut entry = match element? {
+                    Some(element) => element,
+                    None => return Ok(()),
+                };
+                let path = entry.get_full_bundle_path().with_extension(extension);
+                let doc = match entry.data {
+                    bundle::BundleElementData::Document(ref mut doc) => doc,
+                    bundle::BundleElementData::Asset(data) => {
+                        let name = entry.name;
+                        tx.send(Some(Packet::Asset { name, data }))?;
+                        return Ok(());
+                    }
+                    bundle::BundleElementData::Diagnostics(diagnostics) => {
+                        let name = entry.name;
+                        tx.send(Some(Packet::Diagnostics { name, diagnostics }))?;
+                        return Ok(());
+                    }
+                };
+
+                passes.run(doc);
+                diagnostics.extend(passes.take_diagnostics());
+                if let Some(page_facets) = &doc.facets {
+                    if !self.config.facet_taxonomy.is_empty() {
+                        let problems = facets::validate(page_facets, &self.config.facet_taxonomy);
+                        for problem in &problems {
+                            log::warn!("{}: {}", doc.page_id, problem.message());
+                        }
+                        diagnostics.extend(problems);
+                    }
+                    if self.config.facet_index {
+                        facet_index.lock().unwrap().add(&doc.page_id, page_facets);
+                    }
+                }
+                if previous_pages.is_some() {
+                    pages
+                        .lock()
+                        .unwrap()
+                        .push(redirects::PageInfo::from_document(doc));
+                }
+                if self.config.sitemap_base_url.is_some() && !sitemap::is_noindex(doc) {
+                    sitemap_pages.lock().unwrap().push(doc.page_id.to_owned());
+                }
+                if self.config.search_index {
+                    search_records
+                        .lock()
+                        .unwrap()
+                        .push(search::index_document(doc, &namespace));
+                }
+                if !diagnostics.is_empty() {
+                    tx.send(Some(Packet::Diagnostics {
+                        name: entry.name.to_owned(),
+                        diagnostics,
+                    }))?;
+                }
+
+                // Serialize here rather than on the writer thread, which would otherwise be
+                // a bottleneck
+                let data = format
+                    .encode(doc)
+                    .with_context(|| format!("Error serializing {}", doc.page_id))?;
+                let path = path
+                    .to_str()
+                    .with_context(|| format!("Bundle entry name is not UTF-8: {:?}", path))?;
+                tx.send(Some(Packet::Document {
+                    path: path.to_owned(),
+                    data,
+                }))?;
+                Ok(())
+            },
+        );
+
+        // If the writer failed, sending to it did too; its own error says why
+        let _ = tx.send(None);
+        let (mut out_bundle, written) = thread.join().unwrap()?;
+        processed?;
+        {
+            let mut summary = self.summary.lock().unwrap();
+            summary.documents = written.documents;
+            summary.assets_written = written.assets_written;
+            summary.assets_deduplicated = written.assets_deduplicated;
+            summary.diagnostics = written.diagnostics;
+        }
+
+        if self.config.search_index {
+            out_bundle.start_file("search.jsonl")?;
+            search::write_index(&mut search_records.into_inner().unwrap(), &mut out_bundle)?;
+        }
+
+        if self.config.facet_index {
+            out_bundle.start_file("facets.json")?;
+            facet_index.into_inner().unwrap().write(&mut out_bundle)?;
+        }
+
+        if let Some(base_url) = &self.config.sitemap_base_url {
+            for (filename, contents) in
+                sitemap::render(base_url, &sitemap_pages.into_inner().unwrap())
+            {
+                out_bundle.start_file(&filename)?;
+                out_bundle.write_all(contents.as_bytes())?;
+            }
+        }
+
+        if let Some(previous_pages) = previous_pages {
+            let redirects = redirects::find_redirects(previous_pages, &pages.into_inner().unwrap());
+            log::info!("Redirecting {} moved pages", redirects.len());
+            out_bundle.start_file("redirects.json")?;
+            redirects::write_json(&redirects, &mut out_bundle)?;
+            out_bundle.start_file("redirects.nginx.conf")?;
+            redirects::write_nginx(&redirects, &mut out_bundle)?;
+        }
+
+        out_bundle.finish()?;
+        Ok(())
+    }
+
+    /// Whether splicing a document holding this node needs its decoded AST, because the
+    /// transforms or one of the splice passes act on nodes like it. This must cover every node
+    /// which [`BundleSet::splice_passes`] looks at.
+    fn needs_decoding(&self, node_type: &str, node: &bson::RawDocument) -> bool {
+        match node_type {
+            // html_ids; link_check and ref_count; absolute_links and link_check
+            "target" | "ref_role" | "reference" | "named_reference" => true,
+            "substitution_reference" | "block_substitution_reference" => true,
+            "comment" => self.config.strip_comments,
+            "directive" => {
+                // link_check checks toctree entries
+                node.get_array("entries")
+                    .is_ok_and(|entries| entries.into_iter().next().is_some())
+                    || node.get_str("name").is_ok_and(|name| {
+                        self.config
+                            .unwrap_directives
+                            .iter()
+                            .any(|unwrap| unwrap == name)
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// A stage for each bundle through which its documents reach the plugins one at a time and
+    /// in order, while decoding, migration and the passes run across every worker. There are
+    /// none if no plugins are configured.
+    fn plugin_stages(&self) -> Vec<PluginStage> {
+        match self.config.plugins.is_empty() {
+            true => vec![],
+            false => self
+                .bundles
+                .iter()
+                .map(|_| PluginStage::new(&self.config.plugins))
+                .collect(),
+        }
+    }
+
+    /// Migrate a decoded element into its namespace and, if it is a document, run the
+    /// transforms and then the plugins over it, returning the plugins' diagnostics. Linking and
+    /// splicing both do this, so that targets and links which the plugins add are seen by both.
+    /// Every entry takes its turn with the plugins, even one which failed to decode, so that
+    /// the entries behind it are not left waiting.
+    fn prepare_element(
+        &self,
+        element: &mut anyhow::Result<Option<bundle::BundleElement>>,
+        namespace: &str,
+        plugin_stage: Option<&PluginStage>,
+        index: usize,
+    ) -> Vec<bundle::Diagnostic> {
+        let mut document = match element {
+            Ok(Some(element)) => {
+                element.migrate(Path::new(namespace));
+                match &mut element.data {
+                    bundle::BundleElementData::Document(doc) => Some(&mut **doc),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(doc) = &mut document {
+            if self.config.strip_comments {
+                doc.ast.run_transform(&mut transforms::StripComments);
+            }
+            if !self.config.unwrap_directives.is_empty() {
+                doc.ast
+                    .run_transform(&mut transforms::UnwrapDirectives::new(
+                        &self.config.unwrap_directives,
+                    ));
+            }
+        }
+
+        match plugin_stage {
+            Some(stage) => stage.take_turn(index, |plugins| match document {
+                Some(document) => plugin::run_plugins(plugins, document),
+                None => vec![],
+            }),
+            None => vec![],
+        }
+    }
+
+    /// The passes run over each migrated document before it is written. Documents holding
+    /// none of the nodes they act on skip them; see [`BundleSet::needs_decoding`].
+    fn splice_passes(&self, metadata: &bundle::SiteMetadata) -> passes::PassManager<'_> {
+        let mut passes = passes::PassManager::new();
+        passes
+            .register(
+                "html_ids",
+                &["substitutions"],
+                html_ids::HtmlIdPass::new(metadata.project(), None),
+            )
+            .register(
+                "substitutions",
+                &[],
+                substitutions::SubstitutionPass::new(&self.substitutions),
+            )
+            .register(
+                "absolute_links",
+                &[],
+                links::AbsoluteLinkPass::new(&self.config.link_prefixes, &self.db),
+            )
+            .register(
+                "link_check",
+                &["substitutions", "absolute_links"],
+                links::LinkCheckPass::new(&self.db, metadata.project(), &self.link_report),
+            )
+            .register(
+                "ref_count",
+                &["substitutions", "absolute_links"],
+                summary::RefCountPass::new(&self.db, &self.summary),
+            );
+        passes
+    }
+
+    /// Process every entry of every bundle for which `include` returns true: one thread reads
+    /// each bundle's entries in order, and a pool of workers processes them, each keeping the
+    /// state made by `make_state` for the bundle it is working on. Returns the first error, or
+    /// once every entry is done, so global state such as the target database is complete before
+    /// any later phase begins.
+    fn process_entries<I, T, M, F>(
+        &self,
+        phase: &str,
+        include: I,
+        make_state: M,
+        process: F,
+    ) -> anyhow::Result<()>
+    where
+        I: Fn(&bundle::SiteMetadata) -> bool,
+        M: Fn(&bundle::SiteMetadata) -> anyhow::Result<T> + Sync,
+        F: Fn(&mut T, usize, &bundle::SiteMetadata, usize, bundle::RawEntry) -> anyhow::Result<()>
+            + Sync,
+    {
+        let (metadata, totals): (Vec<bundle::SiteMetadata>, Vec<usize>) = self
+            .bundles
+            .iter()
+            .map(|bundle| {
+                let bundle = bundle.lock().unwrap();
+                (bundle.metadata.clone(), bundle.n_entries())
+            })
+            .unzip();
+        let included: Vec<bool> = metadata.iter().map(include).collect();
+        if let Some(first) = included.iter().position(|included| *included) {
+            make_state(&metadata[first])?;
+        }
+
+        // Log each time a bundle passes another tenth of its entries
+        let processed: Vec<AtomicUsize> = totals.iter().map(|_| AtomicUsize::new(0)).collect();
+        let advance = |bundle_index: usize, n: usize| {
+            let total = totals[bundle_index];
+            let done = processed[bundle_index].fetch_add(n, Ordering::Relaxed) + n;
+            if n > 0 && (done == total || (done - n) * 10 / total != done * 10 / total) {
+                log::info!(
+                    "{} {}: {}/{} entries",
+                    phase,
+                    metadata[bundle_index].get_namespace(),
+                    done,
+                    total
+                );
+            }
+        };
+
+        // The first error raised by any thread. Once set, the reader stops sending entries and
+        // the workers stop processing them.
+        let error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
+        let fail = |err: anyhow::Error| {
+            error.lock().unwrap().get_or_insert(err);
+        };
+        let failed = || error.lock().unwrap().is_some();
+
+        let n_cpus = std::thread::available_parallelism()?.get();
+        let mut pool = scoped_threadpool::Pool::new(u32::try_from(n_cpus + 1)?);
+        let (tx, rx) = crossbeam_channel::bounded::<(usize, usize, bundle::RawEntry)>(n_cpus * 4);
+        let (metadata, totals, included, make_state, process, advance, fail, failed) = (
+            &metadata,
+            &totals,
+            &included,
+            &make_state,
+            &process,
+            &advance,
+            &fail,
+            &failed,
+        );
+
         pool.scoped(|scope| {
-            // Chunk our input into a thread pool at bundle granularity
-            for bundle in &self.bundles {
-                scope.execute(|| {
+            scope.execute(move || {
+                for (bundle_index, bundle) in self.bundles.iter().enumerate() {
+                    if !included[bundle_index] {
+                        continue;
+                    }
+
                     let mut bundle = bundle.lock().unwrap();
-                    let bundle_ns = PathBuf::from(bundle.metadata.get_namespace());
-                    for entry in bundle.into_iter() {
-                        let mut entry = entry.unwrap();
-                        entry.migrate(&bundle_ns);
-                        tx.send(Some(entry)).unwrap();
+                    let mut sent = 0;
+                    for entry in bundle.raw_entries() {
+                        let entry = match entry {
+                            Ok(entry) => entry,
+                            Err(err) => return fail(err),
+                        };
+
+                        // Stop if every worker has failed, or if any has
+                        if failed() || tx.send((bundle_index, sent, entry)).is_err() {
+                            return;
+                        }
+                        sent += 1;
+                    }
+
+                    // Account for entries with nothing to read, such as directories
+                    advance(bundle_index, totals[bundle_index] - sent);
+                }
+            });
+
+            for _ in 0..n_cpus {
+                let rx = rx.clone();
+                scope.execute(move || {
+                    let mut state: Option<(usize, T)> = None;
+                    for (bundle_index, index, entry) in rx {
+                        let metadata = &metadata[bundle_index];
+                        let state = match &mut state {
+                            Some((state_index, state)) if *state_index == bundle_index => state,
+                            _ => match make_state(metadata) {
+                                Ok(new_state) => &mut state.insert((bundle_index, new_state)).1,
+                                Err(err) => return fail(err),
+                            },
+                        };
+                        if let Err(err) = process(state, bundle_index, metadata, index, entry) {
+                            return fail(err);
+                        }
+
+                        advance(bundle_index, 1);
                     }
                 });
             }
+
+            // Let the reader notice if every worker has stopped
+            drop(rx);
         });
 
-        tx.send(None)?;
-        thread.join().unwrap()?;
+        match error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
 
+    /// Run a set of passes over every document in every included bundle, discarding their
+    /// diagnostics.
+    fn run_global_phase<'a, I, F>(
+        &self,
+        phase: &str,
+        include: I,
+        make_passes: F,
+    ) -> anyhow::Result<()>
+    where
+        I: Fn(&bundle::SiteMetadata) -> bool,
+        F: Fn(&bundle::SiteMetadata) -> passes::PassManager<'a> + Sync,
+    {
+        let make_state = |metadata: &bundle::SiteMetadata| {
+            let mut passes = make_passes(metadata);
+            passes.schedule()?;
+            log::debug!(
+                "Running passes over {}: {:?}",
+                metadata.get_namespace(),
+                passes.order()
+            );
+            Ok(passes)
+        };
+        self.process_entries(phase, include, make_state, |passes, _, _, _, entry| {
+            let element = match entry.decode() {
+                Some(element) => element?,
+                None => return Ok(()),
+            };
+            if let bundle::BundleElementData::Document(mut doc) = element.data {
+                passes.run(&mut doc);
+                // Diagnostics are raised again when splicing, which is when they are
+                // written out
+                passes.take_diagnostics();
+            }
+            Ok(())
+        })
+    }
+
+    /// Collect the substitution definitions of the configured substitutions project, so that
+    /// they can be expanded while linking. Pages are analyzed in no particular order, so the
+    /// definitions are merged in a fixed one afterwards.
+    fn collect_substitutions(&mut self) -> anyhow::Result<()> {
+        let project = match &self.config.substitutions_project {
+            Some(project) => project,
+            None => return Ok(()),
+        };
+
+        let collected = Mutex::new(substitutions::CollectedDefinitions::new());
+        self.run_global_phase(
+            "Collecting substitutions from",
+            |metadata| metadata.project() == project,
+            |metadata| {
+                let mut passes = passes::PassManager::new();
+                passes.register(
+                    "substitution_definitions",
+                    &[],
+                    substitutions::SubstitutionDefinitionPass::new(
+                        &collected,
+                        Path::new(&metadata.get_namespace()),
+                    ),
+                );
+                passes
+            },
+        )?;
+
+        for conflict in self.substitutions.merge(collected.into_inner().unwrap()) {
+            log::warn!("{}", conflict);
+        }
         Ok(())
     }
 
     pub fn link(&mut self) -> anyhow::Result<()> {
-        let n_cpus = std::thread::available_parallelism()?.get();
-        let mut pool = scoped_threadpool::Pool::new(u32::try_from(n_cpus)?);
+        self.collect_substitutions()?;
 
+        // Linking again starts from scratch, so that repeated calls do not accumulate entries
+        self.toctrees = Mutex::new(toctree::TocTreeDatabase::new());
         let db = Mutex::new(target_database::TargetDatabase::new());
+        let html_id_changes = Mutex::new(vec![]);
 
-        pool.scoped(|scope| {
-            for bundle in &self.bundles {
-                scope.execute(|| {
-                    let mut target_analyzer = analyzer::TargetPass1::new(&db);
-                    let mut bundle = bundle.lock().unwrap();
-                    for entry in bundle.into_iter() {
-                        let entry = entry.unwrap();
-                        if let bundle::BundleElementData::Document(mut doc) = entry.data {
-                            doc.ast.run_analyzer(&mut target_analyzer);
-                        }
+        // Toctree entries are recorded before migration, relative to their own project. The
+        // other passes see each document as splice will: migrated, transformed and passed
+        // through the plugins, with substitutions expanded before ids are assigned, so that
+        // targets within them get the same ids here as in the output.
+        let plugin_stages = self.plugin_stages();
+        self.process_entries(
+            "Linking",
+            |_| true,
+            |metadata| {
+                let toctree = toctree::TocTreePass::new(
+                    &self.toctrees,
+                    metadata.project(),
+                    &metadata.get_namespace(),
+                );
+                let mut passes = passes::PassManager::new();
+                passes
+                    .register(
+                        "substitutions",
+                        &[],
+                        substitutions::SubstitutionPass::new(&self.substitutions),
+                    )
+                    .register(
+                        "html_ids",
+                        &["substitutions"],
+                        html_ids::HtmlIdPass::new(metadata.project(), Some(&html_id_changes)),
+                    )
+                    .register("targets", &["html_ids"], analyzer::TargetPass1::new(&db))
+                    .register("anchors", &["targets"], links::AnchorPass::new(&db));
+                passes.schedule()?;
+                Ok((toctree, passes))
+            },
+            |(toctree, passes), bundle_index, metadata, index, entry| {
+                let mut element = entry.decode().transpose();
+                if let Ok(Some(bundle::BundleElement {
+                    data: bundle::BundleElementData::Document(doc),
+                    ..
+                })) = &mut element
+                {
+                    doc.run_analyzer(toctree);
+                }
+
+                // Diagnostics are raised again when splicing, which is when they are written
+                // out
+                self.prepare_element(
+                    &mut element,
+                    &metadata.get_namespace(),
+                    plugin_stages.get(bundle_index),
+                    index,
+                );
+                if let Some(bundle::BundleElement {
+                    data: bundle::BundleElementData::Document(mut doc),
+                    ..
+                }) = element?
+                {
+                    passes.run(&mut doc);
+                    passes.take_diagnostics();
+                }
+                Ok(())
+            },
+        )?;
+
+        self.db = db.into_inner().unwrap();
+        self.summary.lock().unwrap().targets_defined = self.db.n_targets();
+
+        // Bundles finish in whatever order their threads do, so sort before reporting
+        let mut html_id_changes = html_id_changes.into_inner().unwrap();
+        html_id_changes.sort();
+        for change in &html_id_changes {
+            log::warn!(
+                "{}: {} in {} was renamed to {} to avoid a duplicate id; links to #{} will not reach it",
+                change.project,
+                change.original,
+                change.fileid,
+                change.assigned,
+                change.original
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Write;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::nodes;
+    use crate::output::OutputSink;
+
+    /// A page of the atlas project holding the given nodes, as the parser writes it.
+    fn page(page_id: &str, children: Vec<bson::Bson>) -> bson::Document {
+        bson::doc! {
+            "page_id": page_id,
+            "filename": format!("{page_id}.txt"),
+            "ast": {
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "fileid": format!("{page_id}.txt"),
+                "children": children,
+            },
+            "source": "",
+            "static_assets": [],
+        }
+    }
+
+    /// Any number of pages holding nothing.
+    fn empty_pages(n: usize) -> Vec<bson::Document> {
+        (0..n).map(|i| page(&format!("page{i}"), vec![])).collect()
+    }
+
+    /// Write a directory bundle of the given pages for the atlas project.
+    fn write_pages(path: &Path, pages: &[bson::Document]) {
+        let mut sink = output::DirectorySink::create(path).unwrap();
+        sink.start_file("site.bson").unwrap();
+        sink.write_all(&bson::to_vec(&bundle::SiteMetadata::new("atlas", "main")).unwrap())
+            .unwrap();
+        for page in pages {
+            let page_id = page.get_str("page_id").unwrap();
+            sink.start_file(&format!("documents/{page_id}.bson"))
+                .unwrap();
+            sink.write_all(&bson::to_vec(page).unwrap()).unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn relink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("input");
+        let toctree = bson::bson!({
+            "type": "directive",
+            "position": {"start": {"line": 0}},
+            "domain": "",
+            "name": "toctree",
+            "argument": [],
+            "children": [],
+            "entries": [{"slug": "/intro", "title": "Introduction"}],
+        });
+        write_pages(&path, &[page("index", vec![toctree])]);
+
+        let bundle = bundle::Bundle::open(&path).unwrap();
+        let mut bundles = BundleSet::new(std::iter::once(bundle), config::Config::default());
+        bundles.link().unwrap();
+        let first = bundles.merge_toctrees("atlas").unwrap();
+        assert_eq!(first.children.len(), 1);
+
+        // Linking again gives the same tree rather than repeating each page's entries
+        bundles.link().unwrap();
+        assert_eq!(bundles.merge_toctrees("atlas").unwrap(), first);
+    }
+
+    /// Read every document and diagnostic of a bundle as sorted JSON, keyed by its path
+    /// without an extension.
+    fn read_back(path: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut bundle = bundle::Bundle::open(path).unwrap();
+        assert_eq!(bundle.metadata.project(), "mongodb");
+        (&mut bundle)
+            .into_iter()
+            .filter_map(|element| {
+                let element = element.unwrap();
+                let encoded = match &element.data {
+                    bundle::BundleElementData::Document(document) => {
+                        bundle::OutputFormat::Json.encode(document).unwrap()
+                    }
+                    bundle::BundleElementData::Diagnostics(diagnostics) => {
+                        bundle::OutputFormat::Json.encode(diagnostics).unwrap()
                     }
+                    bundle::BundleElementData::Asset(_) => return None,
+                };
+                Some((element.get_full_bundle_path().with_extension(""), encoded))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let undefined = bson::bson!({
+            "type": "substitution_reference",
+            "position": {"start": {"line": 3}},
+            "name": "undefined",
+            "children": [],
+        });
+        write_pages(
+            &root.join("input"),
+            &[page("index", vec![]), page("faq", vec![undefined])],
+        );
+
+        let bundle = bundle::Bundle::open(root.join("input")).unwrap();
+        let mut bundles = BundleSet::new(std::iter::once(bundle), config::Config::default());
+        bundles.link().unwrap();
+        for format in [bundle::OutputFormat::Bson, bundle::OutputFormat::Json] {
+            let path = root.join(format!("output.{}.zip", format.extension()));
+            let output = output::ZipSink::create(&path).unwrap();
+            bundles
+                .splice(
+                    &bundle::SiteMetadata::new("mongodb", "main"),
+                    None,
+                    None,
+                    format,
+                    output,
+                )
+                .unwrap();
+        }
+
+        // A JSON output reads back exactly as its BSON counterpart does
+        let bson_output = read_back(&root.join("output.bson.zip"));
+        assert_eq!(bson_output.len(), 3);
+        assert_eq!(read_back(&root.join("output.json.zip")), bson_output);
+
+        let mut json_bundle = bundle::Bundle::open(root.join("output.json.zip")).unwrap();
+        let site = json_bundle
+            .dump_entry("site.json", bundle::OutputFormat::Json)
+            .unwrap();
+        let site: serde_json::Value = serde_json::from_slice(&site).unwrap();
+        assert_eq!(site["project"], "mongodb");
+
+        let pages = redirects::load_pages(root.join("output.json.zip")).unwrap();
+        assert_eq!(pages.len(), 2);
+    }
+
+    #[test]
+    fn plugins_run_once_per_document() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write_pages(&root.join("input"), &empty_pages(20));
+
+        let mut bundle = bundle::Bundle::open(root.join("input")).unwrap();
+        let namespace = bundle.metadata.get_namespace();
+        let mut expected: Vec<String> = (&mut bundle)
+            .into_iter()
+            .filter_map(|element| match element.unwrap().data {
+                bundle::BundleElementData::Document(document) => {
+                    Some(format!("{}/{}", namespace, document.page_id))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let config = config::Config {
+            plugins: vec![plugin::PluginConfig {
+                name: "banner".to_owned(),
+                command: vec![
+                    plugin::tests::banner_plugin().to_str().unwrap().to_owned(),
+                    "Preview".to_owned(),
+                ],
+                timeout_secs: 10,
+            }],
+            ..config::Config::default()
+        };
+        let bundles = BundleSet::new(std::iter::once(bundle), config);
+        bundles
+            .splice(
+                &bundle::SiteMetadata::new("mongodb", "main"),
+                None,
+                None,
+                bundle::OutputFormat::Bson,
+                output::ZipSink::create(&root.join("output.zip")).unwrap(),
+            )
+            .unwrap();
+
+        // Every document passes through the plugin exactly once
+        let mut output = bundle::Bundle::open(root.join("output.zip")).unwrap();
+        let mut written = vec![];
+        for element in &mut output {
+            if let bundle::BundleElementData::Document(document) = element.unwrap().data {
+                let banners = document
+                    .ast
+                    .data
+                    .children()
+                    .iter()
+                    .filter(|node| {
+                        matches!(&node.data, nodes::NodeData::Directive(directive)
+                            if directive.name == "banner")
+                    })
+                    .count();
+                assert_eq!(banners, 1, "{}", document.page_id);
+                written.push(document.page_id);
+            }
+        }
+        written.sort();
+        expected.sort();
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn plugin_targets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let link = bson::bson!({
+            "type": "ref_role",
+            "position": {"start": {"line": 0}},
+            "domain": "std",
+            "name": "ref",
+            "target": "preview",
+            "flag": "",
+            "children": [],
+            "fileid": ["page0", "std-label-preview"],
+        });
+        write_pages(
+            &root.join("input"),
+            &[page("page0", vec![]), page("page1", vec![link])],
+        );
+
+        let link_report = |plugins: Vec<plugin::PluginConfig>| {
+            let bundle = bundle::Bundle::open(root.join("input")).unwrap();
+            let output = root.join(format!("output{}", plugins.len()));
+            let config = config::Config {
+                plugins,
+                ..config::Config::default()
+            };
+            let mut bundles = BundleSet::new(std::iter::once(bundle), config);
+            bundles.link().unwrap();
+            bundles
+                .splice(
+                    &bundle::SiteMetadata::new("mongodb", "main"),
+                    None,
+                    None,
+                    bundle::OutputFormat::Bson,
+                    output::DirectorySink::create(&output).unwrap(),
+                )
+                .unwrap();
+            bundles.take_link_report()
+        };
+
+        // The link is broken until a plugin adds the target it points to
+        assert_eq!(link_report(vec![]).len(), 1);
+        let plugins = vec![plugin::PluginConfig {
+            name: "banner".to_owned(),
+            command: vec![
+                plugin::tests::banner_plugin().to_str().unwrap().to_owned(),
+                "Preview".to_owned(),
+                "preview".to_owned(),
+            ],
+            timeout_secs: 10,
+        }];
+        assert!(link_report(plugins).is_empty());
+    }
+
+    #[test]
+    fn plugin_stage_order() {
+        let stage = PluginStage::new(&[]);
+        let order = Mutex::new(vec![]);
+
+        // Entries arrive out of order, but take their turns in order
+        std::thread::scope(|scope| {
+            for index in [3, 1, 4, 0, 2, 5] {
+                let (stage, order) = (&stage, &order);
+                scope.spawn(move || {
+                    stage.take_turn(index, |_| order.lock().unwrap().push(index));
                 });
             }
         });
-        Ok(())
+        assert_eq!(order.into_inner().unwrap(), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn verbatim_documents() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let text = bson::bson!({
+            "type": "text",
+            "position": {"start": {"line": 0}},
+            "value": "Hi",
+            "unknown_field": "kept",
+        });
+        let link = bson::bson!({
+            "type": "reference",
+            "position": {"start": {"line": 0}},
+            "children": [text.clone()],
+            "refuri": "/plain",
+            "unknown_field": "kept",
+        });
+        let mut pages = [page("plain", vec![text]), page("linked", vec![link])];
+        for page in &mut pages {
+            page.insert("unknown_field", "kept");
+        }
+        write_pages(&root.join("input"), &pages);
+
+        let bundle = bundle::Bundle::open(root.join("input")).unwrap();
+        let mut bundles = BundleSet::new(std::iter::once(bundle), config::Config::default());
+        bundles.link().unwrap();
+        bundles
+            .splice(
+                &bundle::SiteMetadata::new("mongodb", "main"),
+                None,
+                None,
+                bundle::OutputFormat::Bson,
+                output::DirectorySink::create(&root.join("output")).unwrap(),
+            )
+            .unwrap();
+
+        let read = |name: &str| -> bson::Document {
+            let path = root.join("output/documents/atlas/main").join(name);
+            bson::from_slice(&std::fs::read(path).unwrap()).unwrap()
+        };
+
+        // A page with nothing for the passes to do is migrated without being decoded
+        let plain = read("plain.bson");
+        assert_eq!(plain.get_str("page_id"), Ok("atlas/main/plain"));
+        assert_eq!(plain.get_str("unknown_field"), Ok("kept"));
+        assert_eq!(
+            plain.get_document("ast").unwrap().get_str("fileid"),
+            Ok("atlas/main/plain.txt")
+        );
+
+        // A page with links is decoded, so that they can be rewritten and checked, and keeps
+        // the unknown fields of the page and of its nodes all the same
+        let linked = read("linked.bson");
+        assert_eq!(linked.get_str("page_id"), Ok("atlas/main/linked"));
+        assert_eq!(linked.get_str("unknown_field"), Ok("kept"));
+        let link = linked
+            .get_document("ast")
+            .unwrap()
+            .get_array("children")
+            .unwrap()[0]
+            .as_document()
+            .unwrap();
+        assert_eq!(link.get_str("refuri"), Ok("/atlas/main/plain"));
+        assert_eq!(link.get_str("unknown_field"), Ok("kept"));
+        let text = link.get_array("children").unwrap()[0]
+            .as_document()
+            .unwrap();
+        assert_eq!(text.get_str("unknown_field"), Ok("kept"));
+        assert_eq!(bundles.take_summary().documents, 2);
+    }
+
+    #[test]
+    fn splice_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write_pages(&root.join("input"), &empty_pages(3));
+        std::fs::write(root.join("input/documents/corrupt.bson"), b"corrupt").unwrap();
+
+        let bundle = bundle::Bundle::open(root.join("input")).unwrap();
+        let bundles = BundleSet::new(std::iter::once(bundle), config::Config::default());
+        let err = bundles
+            .splice(
+                &bundle::SiteMetadata::new("mongodb", "main"),
+                None,
+                None,
+                bundle::OutputFormat::Bson,
+                output::DirectorySink::create(&root.join("output")).unwrap(),
+            )
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("corrupt.bson"), "{:#}", err);
     }
 }
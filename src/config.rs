@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::facets;
+use crate::plugin;
+
+/// Stitch-wide settings, loaded from a TOML manifest.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Map absolute URL prefixes, such as "https://www.mongodb.com/docs/atlas/", to the
+    /// namespace of the stitched project serving them.
+    #[serde(default)]
+    pub link_prefixes: HashMap<String, String>,
+
+    /// Plain-text substitutions available to every project, such as product names and
+    /// version numbers. These take precedence over `substitutions_project`.
+    #[serde(default)]
+    pub substitutions: HashMap<String, String>,
+
+    /// A project whose substitution definitions are made available to every project
+    #[serde(default)]
+    pub substitutions_project: Option<String>,
+
+    /// Remove comment nodes from every document
+    #[serde(default)]
+    pub strip_comments: bool,
+
+    /// Directives to replace with their contents, such as "only"
+    #[serde(default)]
+    pub unwrap_directives: Vec<String>,
+
+    /// External programs to run each document through, in order
+    #[serde(default)]
+    pub plugins: Vec<plugin::PluginConfig>,
+
+    /// Write a full-text search index of every page to search.jsonl in the output archive
+    #[serde(default)]
+    pub search_index: bool,
+
+    /// The allowed values of each facet category, and the sub-facets allowed beneath each
+    /// value. If given, pages with other facets are reported.
+    #[serde(default)]
+    pub facet_taxonomy: facets::Taxonomy,
+
+    /// Write the facets of every page to facets.json in the output archive
+    #[serde(default)]
+    pub facet_index: bool,
+
+    /// The public URL of the stitched site. If given, a sitemap of every indexable page is
+    /// written to sitemap.xml in the output archive.
+    #[serde(default)]
+    pub sitemap_base_url: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading config: {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Error parsing config: {}", path.display()))
+    }
+}
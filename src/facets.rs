@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::bundle;
+use crate::nodes;
+
+/// Either a list of a facet category's values, for values without sub-facets, or a table
+/// mapping each value to the sub-facets allowed beneath it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Values {
+    List(Vec<String>),
+    Table(BTreeMap<String, Taxonomy>),
+}
+
+/// A tree of facets, following the structure of the facets themselves: each category maps
+/// its values to the taxonomy of the sub-facets beneath them.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(from = "BTreeMap<String, Values>")]
+pub struct Taxonomy(BTreeMap<String, BTreeMap<String, Taxonomy>>);
+
+impl From<BTreeMap<String, Values>> for Taxonomy {
+    fn from(categories: BTreeMap<String, Values>) -> Self {
+        Self(
+            categories
+                .into_iter()
+                .map(|(category, values)| {
+                    let values = match values {
+                        Values::List(values) => values
+                            .into_iter()
+                            .map(|value| (value, Taxonomy::default()))
+                            .collect(),
+                        Values::Table(values) => values,
+                    };
+                    (category, values)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Taxonomy {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn add(&mut self, facets: &[nodes::Facet]) {
+        for facet in facets {
+            let sub_taxonomy = self
+                .0
+                .entry(facet.category.to_owned())
+                .or_default()
+                .entry(facet.value.to_owned())
+                .or_default();
+
+            if let Some(sub_facets) = &facet.sub_facets {
+                sub_taxonomy.add(sub_facets);
+            }
+        }
+    }
+}
+
+/// Check a page's facets against the allowed taxonomy. Sub-facets are checked against the
+/// taxonomy beneath their parent facet's value. Returns a warning for each unknown category
+/// or value; the sub-facets of an unknown facet are not checked.
+pub fn validate(facets: &[nodes::Facet], taxonomy: &Taxonomy) -> Vec<bundle::Diagnostic> {
+    let mut diagnostics = vec![];
+    validate_within(facets, taxonomy, "", &mut diagnostics);
+    diagnostics
+}
+
+/// Validate facets nested under `parents`, which names their ancestors like
+/// "target_product=atlas > ".
+fn validate_within(
+    facets: &[nodes::Facet],
+    taxonomy: &Taxonomy,
+    parents: &str,
+    diagnostics: &mut Vec<bundle::Diagnostic>,
+) {
+    for facet in facets {
+        let sub_taxonomy = match taxonomy.0.get(&facet.category) {
+            None => {
+                diagnostics.push(bundle::Diagnostic::new(
+                    bundle::Severity::Warning,
+                    0,
+                    format!("Unknown facet category: {}{}", parents, facet.category),
+                ));
+                continue;
+            }
+            Some(values) => match values.get(&facet.value) {
+                None => {
+                    diagnostics.push(bundle::Diagnostic::new(
+                        bundle::Severity::Warning,
+                        0,
+                        format!(
+                            "Unknown value for facet {}{}: {}",
+                            parents, facet.category, facet.value
+                        ),
+                    ));
+                    continue;
+                }
+                Some(sub_taxonomy) => sub_taxonomy,
+            },
+        };
+
+        if let Some(sub_facets) = &facet.sub_facets {
+            let parents = format!("{}{}={} > ", parents, facet.category, facet.value);
+            validate_within(sub_facets, sub_taxonomy, &parents, diagnostics);
+        }
+    }
+}
+
+/// The facets of every page in a stitch, along with every facet in use.
+#[derive(Debug, Default, Serialize)]
+pub struct FacetIndex {
+    taxonomy: Taxonomy,
+    pages: BTreeMap<String, Vec<nodes::Facet>>,
+}
+
+impl FacetIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the facets of a page, by its namespaced page ID.
+    pub fn add(&mut self, page_id: &str, facets: &[nodes::Facet]) {
+        if facets.is_empty() {
+            return;
+        }
+
+        self.taxonomy.add(facets);
+        self.pages.insert(page_id.to_owned(), facets.to_owned());
+    }
+
+    pub fn write(&self, writer: impl Write) -> Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn make_facet(category: &str, value: &str, sub_facets: Vec<nodes::Facet>) -> nodes::Facet {
+        nodes::Facet {
+            category: category.to_owned(),
+            value: value.to_owned(),
+            sub_facets: Some(sub_facets),
+            display_name: value.to_owned(),
+        }
+    }
+
+    #[test]
+    fn validate_and_index() {
+        let taxonomy: Taxonomy = toml::from_str(
+            r#"
+            genre = ["reference"]
+
+            [target_product.compass]
+
+            [target_product.atlas.sub_product]
+            search = {}
+            charts = { programming_language = ["python"] }
+            "#,
+        )
+        .unwrap();
+
+        let facets = vec![
+            make_facet(
+                "target_product",
+                "atlas",
+                vec![
+                    make_facet("sub_product", "search", vec![]),
+                    make_facet(
+                        "sub_product",
+                        "charts",
+                        vec![
+                            make_facet("programming_language", "python", vec![]),
+                            make_facet("programming_language", "java", vec![]),
+                        ],
+                    ),
+                    make_facet("sub_product", "vector-search", vec![]),
+                ],
+            ),
+            // Sub-products are only allowed beneath Atlas
+            make_facet(
+                "target_product",
+                "compass",
+                vec![make_facet("sub_product", "search", vec![])],
+            ),
+            make_facet("genre", "tutorial", vec![]),
+            make_facet("audience", "developer", vec![]),
+        ];
+
+        let messages: Vec<String> = validate(&facets, &taxonomy)
+            .iter()
+            .map(|diagnostic| diagnostic.message().to_owned())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Unknown value for facet target_product=atlas > sub_product=charts > \
+                 programming_language: java",
+                "Unknown value for facet target_product=atlas > sub_product: vector-search",
+                "Unknown facet category: target_product=compass > sub_product",
+                "Unknown value for facet genre: tutorial",
+                "Unknown facet category: audience",
+            ]
+        );
+
+        let mut index = FacetIndex::new();
+        index.add("atlas/main/index", &facets[..1]);
+        index.add("compass/main/index", &[]);
+        let mut out = vec![];
+        index.write(&mut out).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            value["taxonomy"],
+            serde_json::json!({
+                "target_product": {
+                    "atlas": {
+                        "sub_product": {
+                            "charts": {"programming_language": {"java": {}, "python": {}}},
+                            "search": {},
+                            "vector-search": {}
+                        }
+                    }
+                }
+            })
+        );
+        assert_eq!(
+            value["pages"]["atlas/main/index"][0]["value"],
+            serde_json::json!("atlas")
+        );
+        assert!(value["pages"].get("compass/main/index").is_none());
+    }
+}
@@ -0,0 +1,287 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::analyzer::{Analyzer, AnalyzerContext};
+use crate::bundle;
+use crate::nodes;
+
+lazy_static! {
+    static ref PAT_INVALID_ID_CHARACTERS: regex::Regex =
+        regex::Regex::new(r###"[^\w_\.\-]"###).unwrap();
+}
+
+/// Turn an ID into a valid HTML5 element ID.
+fn make_html5_id(orig: &str) -> Cow<'_, str> {
+    let clean_id = PAT_INVALID_ID_CHARACTERS.replace_all(orig, "-");
+    if clean_id.is_empty() {
+        return Cow::from("unnamed");
+    }
+
+    clean_id
+}
+
+/// The HTML id a target would have if nothing else on its page claimed it, or None if the
+/// target has no identifiers.
+pub fn target_base_id(target: &nodes::Target) -> Option<String> {
+    // Frankly, this is silly. We just pick the longest identifier. This is arbitrary,
+    // and we can consider this behavior implementation-defined to be changed later if needed.
+    // It just needs to be something consistent.
+    let chosen_id = target
+        .children
+        .iter()
+        .filter_map(|child| match &child.data {
+            nodes::NodeData::TargetIdentifier(identifier) => {
+                identifier.ids.iter().max_by_key(|id| id.len())
+            }
+            _ => None,
+        })
+        .max_by_key(|candidate| candidate.len())?;
+
+    Some(format!(
+        "{}-{}-{}",
+        target.domain,
+        target.name,
+        make_html5_id(chosen_id)
+    ))
+}
+
+fn collect_heading_ids(node: &nodes::Node, ids: &mut HashSet<String>) {
+    if let nodes::NodeData::Heading(heading) = &node.data {
+        ids.insert(heading.id.to_owned());
+    }
+
+    for child in node.data.children() {
+        collect_heading_ids(child, ids);
+    }
+}
+
+/// Hands out HTML ids which are unique within a single output page. Allocation depends only
+/// on the order in which ids are requested, so walking a page the same way always yields the
+/// same ids.
+#[derive(Default)]
+pub struct HtmlIdAllocator {
+    used: HashSet<String>,
+}
+
+impl HtmlIdAllocator {
+    pub fn new() -> Self {
+        Self {
+            used: HashSet::new(),
+        }
+    }
+
+    /// Create an allocator for a page, reserving the ids of its headings (including those in
+    /// included files) so that no target can take them.
+    pub fn for_page(page: &nodes::Document) -> Self {
+        let mut used = HashSet::new();
+        collect_heading_ids(&page.ast, &mut used);
+        Self { used }
+    }
+
+    /// Claim an id, appending the smallest numeric suffix needed to make it unique.
+    pub fn allocate(&mut self, base: &str) -> String {
+        if self.used.insert(base.to_owned()) {
+            return base.to_owned();
+        }
+
+        (1..)
+            .map(|n| format!("{base}-{n}"))
+            .find(|candidate| self.used.insert(candidate.to_owned()))
+            .unwrap()
+    }
+}
+
+/// A target whose HTML id had to be disambiguated, breaking any link written against its
+/// natural id.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HtmlIdChange {
+    pub project: String,
+    pub page_id: String,
+    pub fileid: String,
+    pub original: String,
+    pub assigned: String,
+}
+
+/// Assign every target a page-unique html_id, in document order. Each target whose id was
+/// changed by disambiguation is raised as a diagnostic and, if given somewhere to report to,
+/// recorded there.
+pub struct HtmlIdPass<'a> {
+    project: String,
+    allocator: HtmlIdAllocator,
+    page_id: String,
+    changes: Option<&'a Mutex<Vec<HtmlIdChange>>>,
+    diagnostics: Vec<bundle::Diagnostic>,
+}
+
+impl<'a> HtmlIdPass<'a> {
+    pub fn new(project: &str, changes: Option<&'a Mutex<Vec<HtmlIdChange>>>) -> Self {
+        Self {
+            project: project.to_owned(),
+            allocator: HtmlIdAllocator::new(),
+            page_id: String::new(),
+            changes,
+            diagnostics: vec![],
+        }
+    }
+}
+
+impl<'a> Analyzer for HtmlIdPass<'a> {
+    fn enter_page(&mut self, _context: &AnalyzerContext, page: &nodes::Document) {
+        self.allocator = HtmlIdAllocator::for_page(page);
+        self.page_id = page.page_id.to_owned();
+    }
+
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        let line = node.position().line();
+        let target = match &mut node.data {
+            nodes::NodeData::Target(target) => target,
+            _ => return,
+        };
+
+        let base = match target_base_id(target) {
+            Some(base) => base,
+            None => return,
+        };
+
+        let assigned = self.allocator.allocate(&base);
+        if assigned != base {
+            self.diagnostics.push(bundle::Diagnostic::new(
+                bundle::Severity::Warning,
+                line,
+                format!(
+                    "{} was renamed to {} to avoid a duplicate id; links to #{} will not reach it",
+                    base, assigned, base
+                ),
+            ));
+
+            if let Some(changes) = self.changes {
+                changes.lock().unwrap().push(HtmlIdChange {
+                    project: self.project.to_owned(),
+                    page_id: self.page_id.to_owned(),
+                    fileid: context
+                        .get_current()
+                        .expect("Analysis started at non-root node")
+                        .as_posix(),
+                    original: base,
+                    assigned: assigned.to_owned(),
+                });
+            }
+        }
+
+        target.html_id = Some(assigned);
+    }
+
+    fn take_diagnostics(&mut self) -> Vec<bundle::Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn allocate() {
+        let mut allocator = HtmlIdAllocator::new();
+        assert_eq!(allocator.allocate("std-label-a"), "std-label-a");
+        assert_eq!(allocator.allocate("std-label-a-1"), "std-label-a-1");
+        // A suffix that is already taken by an explicit id is skipped
+        assert_eq!(allocator.allocate("std-label-a"), "std-label-a-2");
+        assert_eq!(allocator.allocate("std-label-a"), "std-label-a-3");
+        assert_eq!(allocator.allocate("std-label-b"), "std-label-b");
+    }
+
+    fn make_heading(line: i32, id: &str) -> bson::Bson {
+        bson::bson!({
+            "type": "heading",
+            "position": {"start": {"line": line}},
+            "id": id,
+            "children": []
+        })
+    }
+
+    #[test]
+    fn assign_ids() {
+        let mut doc: nodes::Document = bson::from_bson(bson::bson!({
+            "page_id": "index",
+            "filename": "index.txt",
+            "source": "",
+            "static_assets": [],
+            "ast": {
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "fileid": "index.txt",
+                "children": [
+                    nodes::tests::make_target(1, "install"),
+                    nodes::tests::make_target(2, "overview"),
+                    {
+                        "type": "root",
+                        "position": {"start": {"line": 3}},
+                        "fileid": "includes/install.rst",
+                        "children": [nodes::tests::make_target(4, "install")]
+                    },
+                    {
+                        "type": "section",
+                        "position": {"start": {"line": 5}},
+                        "children": [make_heading(5, "std-label-overview")]
+                    }
+                ]
+            }
+        }))
+        .unwrap();
+
+        let changes = Mutex::new(vec![]);
+        let mut pass = HtmlIdPass::new("atlas", Some(&changes));
+        doc.run_analyzer(&mut pass);
+
+        let mut assigned = vec![];
+        doc.ast.for_each(&mut |node: &mut nodes::Node| {
+            if let nodes::NodeData::Target(target) = &node.data {
+                assigned.push(target.html_id.to_owned().unwrap());
+            }
+        });
+        // Heading ids are reserved even though the heading comes after the target
+        assert_eq!(
+            assigned,
+            vec![
+                "std-label-install",
+                "std-label-overview-1",
+                "std-label-install-1"
+            ]
+        );
+
+        let lines: Vec<i32> = pass
+            .take_diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.start())
+            .collect();
+        assert_eq!(lines, vec![2, 4]);
+
+        drop(pass);
+        let changes = changes.into_inner().unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                HtmlIdChange {
+                    project: "atlas".to_owned(),
+                    page_id: "index".to_owned(),
+                    fileid: "index.txt".to_owned(),
+                    original: "std-label-overview".to_owned(),
+                    assigned: "std-label-overview-1".to_owned(),
+                },
+                HtmlIdChange {
+                    project: "atlas".to_owned(),
+                    page_id: "index".to_owned(),
+                    fileid: "includes/install.rst".to_owned(),
+                    original: "std-label-install".to_owned(),
+                    assigned: "std-label-install-1".to_owned(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,25 @@
+#![forbid(unsafe_code)]
+
+pub mod analyzer;
+pub mod bundle;
+pub mod bundle_set;
+pub mod config;
+pub mod facets;
+pub mod html_ids;
+pub mod links;
+pub mod markdown;
+pub mod nodes;
+pub mod output;
+pub mod passes;
+pub mod plugin;
+pub mod redirects;
+pub mod render;
+pub mod search;
+pub mod sitemap;
+pub mod substitutions;
+pub mod summary;
+#[cfg(any(test, feature = "synthetic"))]
+pub mod synthetic;
+pub mod target_database;
+pub mod toctree;
+pub mod transforms;
@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{Analyzer, AnalyzerContext};
+use crate::nodes;
+use crate::target_database;
+
+lazy_static! {
+    static ref PAT_URI_SCHEME: regex::Regex =
+        regex::Regex::new(r###"^[a-zA-Z][a-zA-Z0-9+.\-]*:"###).unwrap();
+}
+
+/// Return true if a refuri points within the stitched site, rather than being an external
+/// link (anything with a URI scheme, such as http(s)://) or a same-page anchor.
+pub fn is_internal_refuri(refuri: &str) -> bool {
+    !(refuri.is_empty()
+        || refuri.starts_with('#')
+        || refuri.starts_with("//")
+        || PAT_URI_SCHEME.is_match(refuri))
+}
+
+/// Split a link into its path and fragment, discarding any query string.
+pub(crate) fn split_fragment(uri: &str) -> (&str, Option<&str>) {
+    let (uri, fragment) = match uri.split_once('#') {
+        Some((uri, fragment)) => (uri, Some(fragment)),
+        None => (uri, None),
+    };
+
+    let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+    (path, fragment)
+}
+
+/// Rewrite hardcoded absolute links into other projects of this stitch, such as
+/// "https://www.mongodb.com/docs/atlas/foo/", into namespaced internal links like
+/// "/atlas/main/foo". Links are only rewritten if the page they point at exists.
+pub struct AbsoluteLinkPass<'a> {
+    /// URL prefixes and the namespace they map to, longest prefix first
+    prefixes: Vec<(&'a str, &'a str)>,
+    db: &'a target_database::TargetDatabase,
+}
+
+impl<'a> AbsoluteLinkPass<'a> {
+    pub fn new(
+        prefixes: &'a HashMap<String, String>,
+        db: &'a target_database::TargetDatabase,
+    ) -> Self {
+        let mut prefixes: Vec<(&str, &str)> = prefixes
+            .iter()
+            .map(|(prefix, namespace)| (prefix.as_str(), namespace.trim_matches('/')))
+            .collect();
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Self { prefixes, db }
+    }
+
+    fn rewrite(&self, refuri: &str) -> Option<String> {
+        let (namespace, remainder) = self
+            .prefixes
+            .iter()
+            .find_map(|(prefix, namespace)| Some((*namespace, refuri.strip_prefix(prefix)?)))?;
+
+        let (path, fragment) = split_fragment(remainder);
+
+        self.db
+            .resolve_page(&format!("{namespace}/{path}"))
+            .map(|page_id| match fragment {
+                Some(fragment) => format!("/{page_id}#{fragment}"),
+                None => format!("/{page_id}"),
+            })
+    }
+}
+
+impl<'a> Analyzer for AbsoluteLinkPass<'a> {
+    fn enter_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) {
+        let refuri = match &mut node.data {
+            nodes::NodeData::Reference(reference) => &mut reference.refuri,
+            nodes::NodeData::NamedReference(reference) => &mut reference.refuri,
+            _ => return,
+        };
+
+        if let Some(new_refuri) = self.rewrite(refuri) {
+            log::debug!("Rewrote absolute link {} to {}", refuri, new_refuri);
+            *refuri = new_refuri;
+        }
+    }
+}
+
+/// Record each page and the HTML ids defined on it, so that links to them can be checked.
+/// This must run over migrated documents, after [`crate::analyzer::TargetPass1`] has assigned
+/// target ids.
+pub struct AnchorPass<'a> {
+    db: &'a Mutex<target_database::TargetDatabase>,
+}
+
+impl<'a> AnchorPass<'a> {
+    pub fn new(db: &'a Mutex<target_database::TargetDatabase>) -> Self {
+        Self { db }
+    }
+
+    fn get_page_id(&self, context: &AnalyzerContext) -> String {
+        context
+            .get_root()
+            .expect("Analysis started at non-root node")
+            .without_known_suffix()
+    }
+}
+
+impl<'a> Analyzer for AnchorPass<'a> {
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        let html_id = match &node.data {
+            nodes::NodeData::Root(_) if context.depth() == 0 => {
+                let page_id = self.get_page_id(context);
+                self.db.lock().unwrap().define_page(page_id);
+                return;
+            }
+            nodes::NodeData::Target(target) => match &target.html_id {
+                Some(html_id) => html_id,
+                None => return,
+            },
+            nodes::NodeData::Heading(heading) => &heading.id,
+            _ => return,
+        };
+
+        let page_id = self.get_page_id(context);
+        self.db.lock().unwrap().define_anchor(&page_id, html_id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrokenLinkReason {
+    MissingPage,
+    MissingAnchor,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BrokenLink {
+    /// The namespaced page containing the link
+    pub page: String,
+
+    /// The link target, as it appears in the stitched output
+    pub target: String,
+
+    pub reason: BrokenLinkReason,
+}
+
+/// Every broken internal link in a stitched site, grouped by the project containing it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LinkReport {
+    pub projects: BTreeMap<String, BTreeSet<BrokenLink>>,
+}
+
+impl LinkReport {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Error opening link report: {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Error parsing link report: {}", path.display()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, project: &str, link: BrokenLink) {
+        self.projects
+            .entry(project.to_owned())
+            .or_default()
+            .insert(link);
+    }
+
+    pub fn len(&self) -> usize {
+        self.projects.values().map(|links| links.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the broken links which are not already present in a baseline report.
+    pub fn difference(&self, baseline: &LinkReport) -> LinkReport {
+        let mut result = LinkReport::default();
+        for (project, links) in &self.projects {
+            for link in links {
+                let known = baseline
+                    .projects
+                    .get(project)
+                    .is_some_and(|baseline_links| baseline_links.contains(link));
+                if !known {
+                    result.add(project, link.clone());
+                }
+            }
+        }
+
+        result
+    }
+
+    pub fn log(&self) {
+        for (project, links) in &self.projects {
+            log::warn!("{}: {} broken links", project, links.len());
+            for link in links {
+                log::warn!("  {} -> {} ({:?})", link.page, link.target, link.reason);
+            }
+        }
+    }
+}
+
+/// Check every internal link in a migrated document against the pages and anchors
+/// collected while linking.
+pub struct LinkCheckPass<'a> {
+    db: &'a target_database::TargetDatabase,
+    project: String,
+    report: &'a Mutex<LinkReport>,
+}
+
+impl<'a> LinkCheckPass<'a> {
+    pub fn new(
+        db: &'a target_database::TargetDatabase,
+        project: &str,
+        report: &'a Mutex<LinkReport>,
+    ) -> Self {
+        Self {
+            db,
+            project: project.to_owned(),
+            report,
+        }
+    }
+
+    fn check(&self, page: &str, target: &str, target_page: &str, html_id: Option<&str>) {
+        let reason = match self.db.resolve_page(target_page) {
+            None => BrokenLinkReason::MissingPage,
+            Some(target_page) => match html_id {
+                Some(html_id) if !html_id.is_empty() => {
+                    if self.db.has_anchor(&target_page, html_id) {
+                        return;
+                    }
+                    BrokenLinkReason::MissingAnchor
+                }
+                _ => return,
+            },
+        };
+
+        self.report.lock().unwrap().add(
+            &self.project,
+            BrokenLink {
+                page: page.to_owned(),
+                target: target.to_owned(),
+                reason,
+            },
+        );
+    }
+}
+
+impl<'a> Analyzer for LinkCheckPass<'a> {
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        let page = context
+            .get_root()
+            .expect("Analysis started at non-root node")
+            .without_known_suffix();
+
+        match &node.data {
+            nodes::NodeData::RefRole(refrole) => {
+                if let Some((fileid, html_id)) = &refrole.fileid {
+                    let target = format!("{fileid}#{html_id}");
+                    self.check(&page, &target, fileid, Some(html_id));
+                }
+            }
+            nodes::NodeData::Reference(nodes::Reference { refuri, .. })
+            | nodes::NodeData::NamedReference(nodes::NamedReference { refuri, .. }) => {
+                if let Some(html_id) = refuri.strip_prefix('#') {
+                    self.check(&page, refuri, &page, Some(html_id));
+                } else if is_internal_refuri(refuri) {
+                    let (path, fragment) = split_fragment(refuri);
+                    self.check(&page, refuri, path, fragment);
+                }
+            }
+            nodes::NodeData::Directive(directive) => {
+                for entry in directive.entries.iter().flatten() {
+                    if let (Some(slug), None) = (&entry.slug, &entry.ref_project) {
+                        self.check(&page, slug, slug, None);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite() {
+        let mut db = target_database::TargetDatabase::new();
+        db.define_page("atlas/main/index".to_owned());
+        db.define_page("atlas/main/getting-started".to_owned());
+        db.define_page("atlas/main/security/index".to_owned());
+        db.define_page("manual/main/aggregation".to_owned());
+
+        let prefixes: HashMap<String, String> = [
+            ("https://www.mongodb.com/docs/", "manual/main"),
+            ("https://www.mongodb.com/docs/atlas/", "atlas/main/"),
+        ]
+        .into_iter()
+        .map(|(prefix, ns)| (prefix.to_owned(), ns.to_owned()))
+        .collect();
+        let pass = AbsoluteLinkPass::new(&prefixes, &db);
+
+        assert_eq!(
+            pass.rewrite("https://www.mongodb.com/docs/atlas/getting-started/#connect"),
+            Some("/atlas/main/getting-started#connect".to_owned())
+        );
+        assert_eq!(
+            pass.rewrite("https://www.mongodb.com/docs/atlas/security/?tab=x"),
+            Some("/atlas/main/security/index".to_owned())
+        );
+        assert_eq!(
+            pass.rewrite("https://www.mongodb.com/docs/atlas/"),
+            Some("/atlas/main/index".to_owned())
+        );
+        assert_eq!(
+            pass.rewrite("https://www.mongodb.com/docs/aggregation/"),
+            Some("/manual/main/aggregation".to_owned())
+        );
+        assert_eq!(
+            pass.rewrite("https://www.mongodb.com/docs/atlas/does-not-exist/"),
+            None
+        );
+        assert_eq!(pass.rewrite("https://example.com/atlas/"), None);
+    }
+
+    #[test]
+    fn check_links() {
+        let mut db = target_database::TargetDatabase::new();
+        db.define_page("atlas/main/index".to_owned());
+        db.define_page("atlas/main/getting-started".to_owned());
+        db.define_anchor("atlas/main/getting-started", "std-label-connect");
+        db.define_anchor("atlas/main/index", "overview");
+
+        let mut ast: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "atlas/main/index.txt",
+            "children": [
+                {
+                    "type": "ref_role",
+                    "position": {"start": {"line": 0}},
+                    "children": [],
+                    "domain": "std",
+                    "name": "label",
+                    "target": "connect",
+                    "flag": "",
+                    "fileid": ["atlas/main/getting-started", "std-label-connect"]
+                },
+                {
+                    "type": "ref_role",
+                    "position": {"start": {"line": 0}},
+                    "children": [],
+                    "domain": "std",
+                    "name": "label",
+                    "target": "gone",
+                    "flag": "",
+                    "fileid": ["atlas/main/getting-started", "std-label-gone"]
+                },
+                {
+                    "type": "reference",
+                    "position": {"start": {"line": 0}},
+                    "children": [],
+                    "refuri": "/atlas/main/missing-page"
+                },
+                {
+                    "type": "reference",
+                    "position": {"start": {"line": 0}},
+                    "children": [],
+                    "refuri": "#overview"
+                },
+                {
+                    "type": "reference",
+                    "position": {"start": {"line": 0}},
+                    "children": [],
+                    "refuri": "https://example.com/missing-page"
+                },
+                {
+                    "type": "directive",
+                    "position": {"start": {"line": 0}},
+                    "domain": "",
+                    "name": "toctree",
+                    "argument": [],
+                    "children": [],
+                    "entries": [
+                        {"slug": "/atlas/main/getting-started"},
+                        {"slug": "/atlas/main/removed"},
+                        {"slug": "/not-checked", "ref_project": "manual"}
+                    ]
+                }
+            ]
+        }))
+        .unwrap();
+
+        let report = Mutex::new(LinkReport::default());
+        ast.run_analyzer(&mut LinkCheckPass::new(&db, "atlas", &report));
+        let report = report.into_inner().unwrap();
+
+        let broken: Vec<(&str, BrokenLinkReason)> = report.projects["atlas"]
+            .iter()
+            .map(|link| (link.target.as_str(), link.reason))
+            .collect();
+        assert_eq!(
+            broken,
+            vec![
+                ("/atlas/main/missing-page", BrokenLinkReason::MissingPage),
+                ("/atlas/main/removed", BrokenLinkReason::MissingPage),
+                (
+                    "atlas/main/getting-started#std-label-gone",
+                    BrokenLinkReason::MissingAnchor
+                ),
+            ]
+        );
+
+        let mut baseline = LinkReport::default();
+        baseline.add(
+            "atlas",
+            BrokenLink {
+                page: "atlas/main/index".to_owned(),
+                target: "/atlas/main/removed".to_owned(),
+                reason: BrokenLinkReason::MissingPage,
+            },
+        );
+        assert_eq!(report.difference(&baseline).len(), 2);
+    }
+}
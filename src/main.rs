@@ -1,49 +1,298 @@
 #![forbid(unsafe_code)]
 
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use anyhow::Result;
 use clap::Parser;
 
-mod analyzer;
-mod bundle;
-mod bundle_set;
-mod nodes;
-mod target_database;
+#[cfg(feature = "synthetic")]
+use stitcher::synthetic;
+use stitcher::{bundle, bundle_set, config, links, output, redirects, render, summary};
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Render a stitched bundle into a directory of static HTML or Markdown pages
+    Render {
+        /// The stitched bundle to render
+        bundle: PathBuf,
+
+        /// The directory to write pages into
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+
+        /// The format to write pages in
+        #[arg(long, value_enum, default_value_t = render::Format::Html)]
+        format: render::Format,
+    },
+
+    /// Print a single entry of a bundle, such as "documents/index.bson", for inspection
+    Dump {
+        /// The bundle containing the entry
+        bundle: PathBuf,
+
+        /// The path of the entry within the bundle
+        entry: String,
+
+        /// The format to print the entry in
+        #[arg(long, value_enum, default_value_t = bundle::OutputFormat::Json)]
+        format: bundle::OutputFormat,
+    },
+
+    /// Generate a synthetic bundle for benchmarking and profiling
+    #[cfg(feature = "synthetic")]
+    Generate {
+        /// The project name to give the bundle
+        project: String,
+
+        /// The path to write the bundle to: a zip archive if it ends in .zip, and otherwise a
+        /// directory
+        #[arg(short, long, value_name = "PATH")]
+        output: PathBuf,
+
+        /// Number of pages
+        #[arg(long, default_value_t = 100)]
+        pages: usize,
+
+        /// Number of targets defined on each page
+        #[arg(long, default_value_t = 10)]
+        targets_per_page: usize,
+
+        /// Number of refs to other pages' targets on each page
+        #[arg(long, default_value_t = 20)]
+        refs_per_page: usize,
+
+        /// Number of distinct assets
+        #[arg(long, default_value_t = 10)]
+        assets: usize,
+
+        /// Number of diagnostics reported for each page
+        #[arg(long, default_value_t = 1)]
+        diagnostics_per_page: usize,
+    },
+}
 
 #[derive(clap::Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    /// Bundles to operate on
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Bundles to operate on: zip, tar or tar.gz archives, or unpacked directories. A
+    /// bundle of "-" is read from stdin
     bundles: Vec<PathBuf>,
 
-    /// The path to which to save the stitched bundle
+    /// The path to which to save the stitched bundle: a zip archive if it ends in .zip,
+    /// and otherwise a new or empty directory
+    #[arg(short, long, value_name = "PATH", required = true)]
+    output: Option<PathBuf>,
+
+    /// The format in which to write documents, diagnostics and site metadata
+    #[arg(long, value_enum, default_value_t = bundle::OutputFormat::Bson)]
+    output_format: bundle::OutputFormat,
+
+    /// A TOML manifest configuring this stitch
     #[arg(short, long, value_name = "FILE")]
-    output: PathBuf,
+    config: Option<PathBuf>,
+
+    /// Merge each bundle's toctree into a single navigation tree rooted at this project, or
+    /// at this namespace if the project has several branches, e.g. "atlas/v2"
+    #[arg(long, value_name = "PROJECT")]
+    umbrella: Option<String>,
+
+    /// Write a JSON report of broken internal links to this path
+    #[arg(long, value_name = "FILE")]
+    link_report: Option<PathBuf>,
+
+    /// A previous link report; broken links already listed there are not considered new
+    #[arg(long, value_name = "FILE")]
+    link_baseline: Option<PathBuf>,
+
+    /// Fail if any new broken internal links are found
+    #[arg(long)]
+    fail_on_broken_links: bool,
+
+    /// A previous stitched output; pages which have since moved are written to redirects.json
+    /// and redirects.nginx.conf in the new output
+    #[arg(long, value_name = "FILE")]
+    previous_output: Option<PathBuf>,
+
+    /// Write a JSON summary of what was stitched, and how long each phase took, to this path
+    #[arg(long, value_name = "FILE")]
+    summary_json: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+fn render(bundle: &Path, output: &Path, format: render::Format) -> Result<()> {
+    let n_pages = render::render_bundle(bundle, output, format)?;
+    log::info!("Rendered {} pages into {}", n_pages, output.display());
+    Ok(())
+}
 
-    let cli = Cli::parse();
+fn dump(bundle: &Path, entry: &str, format: bundle::OutputFormat) -> Result<()> {
+    let mut bundle = bundle::Bundle::open(bundle)?;
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&bundle.dump_entry(entry, format)?)?;
+    if format == bundle::OutputFormat::Json {
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
 
-    let output_file = File::create(cli.output)?;
-    let output_writer = BufWriter::new(output_file);
-    let output_archive = zip::ZipWriter::new(output_writer);
+#[cfg(feature = "synthetic")]
+fn generate(generator: &synthetic::Generator, output: &Path) -> Result<()> {
+    // Check before creating the output, so that nothing is left behind
+    generator.validate()?;
+    if output
+        .extension()
+        .is_some_and(|extension| extension == "zip")
+    {
+        generator.write(output::ZipSink::create(output)?)
+    } else {
+        generator.write(output::DirectorySink::create(output)?)
+    }
+}
+
+fn stitch(cli: &Cli) -> Result<()> {
+    if cli
+        .bundles
+        .iter()
+        .filter(|path| *path == Path::new("-"))
+        .count()
+        > 1
+    {
+        anyhow::bail!("Only one bundle can be read from stdin");
+    }
+
+    let mut phases = vec![];
+    let phase_start = Instant::now();
 
     let mut bundles = vec![];
-    for path in cli.bundles {
-        let bundle = bundle::Bundle::open(&path)?;
+    for path in &cli.bundles {
+        let bundle = bundle::Bundle::open(path)?;
         bundles.push(bundle);
     }
 
-    let mut bundles = bundle_set::BundleSet::new(bundles.into_iter());
+    let config = match &cli.config {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+
+    let previous_pages = match &cli.previous_output {
+        Some(path) => Some(redirects::load_pages(path)?),
+        None => None,
+    };
+
+    let mut bundles = bundle_set::BundleSet::new(bundles.into_iter(), config);
+    phases.push(summary::PhaseTiming::new("open", phase_start.elapsed()));
 
+    let phase_start = Instant::now();
     let site_metadata = bundle::SiteMetadata::new("mongodb", "main");
     bundles.link()?;
-    bundles.splice(&site_metadata, output_archive)?;
+
+    let toctree = match &cli.umbrella {
+        Some(umbrella) => Some(bundles.merge_toctrees(umbrella)?),
+        None => None,
+    };
+    phases.push(summary::PhaseTiming::new("link", phase_start.elapsed()));
+
+    let phase_start = Instant::now();
+
+    let output = cli.output.as_ref().expect("Output path is required");
+    if output
+        .extension()
+        .is_some_and(|extension| extension == "zip")
+    {
+        bundles.splice(
+            &site_metadata,
+            toctree.as_ref(),
+            previous_pages.as_deref(),
+            cli.output_format,
+            output::ZipSink::create(output)?,
+        )?;
+    } else {
+        bundles.splice(
+            &site_metadata,
+            toctree.as_ref(),
+            previous_pages.as_deref(),
+            cli.output_format,
+            output::DirectorySink::create(output)?,
+        )?;
+    }
+    phases.push(summary::PhaseTiming::new("splice", phase_start.elapsed()));
+
+    let mut summary = bundles.take_summary();
+    summary.phases = phases;
+    summary.log();
+    if let Some(path) = &cli.summary_json {
+        summary.save(path)?;
+    }
+
+    let link_report = bundles.take_link_report();
+    if let Some(path) = &cli.link_report {
+        link_report.save(path)?;
+    }
+
+    let new_broken_links = match &cli.link_baseline {
+        Some(path) => link_report.difference(&links::LinkReport::load(path)?),
+        None => link_report,
+    };
+    new_broken_links.log();
+    if cli.fail_on_broken_links && !new_broken_links.is_empty() {
+        anyhow::bail!("Found {} new broken links", new_broken_links.len());
+    }
 
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    // Stitching logs its progress and summary at the info level, so show them by default.
+    // Other commands keep env_logger's quieter default.
+    let env = env_logger::Env::default();
+    let env = match cli.command {
+        None => env.default_filter_or("info"),
+        Some(_) => env,
+    };
+    env_logger::Builder::from_env(env).init();
+    match &cli.command {
+        Some(Command::Render {
+            bundle,
+            output,
+            format,
+        }) => render(bundle, output, *format),
+        Some(Command::Dump {
+            bundle,
+            entry,
+            format,
+        }) => dump(bundle, entry, *format),
+        #[cfg(feature = "synthetic")]
+        Some(Command::Generate {
+            project,
+            output,
+            pages,
+            targets_per_page,
+            refs_per_page,
+            assets,
+            diagnostics_per_page,
+        }) => generate(
+            &synthetic::Generator {
+                pages: *pages,
+                targets_per_page: *targets_per_page,
+                refs_per_page: *refs_per_page,
+                assets: *assets,
+                diagnostics_per_page: *diagnostics_per_page,
+                ..synthetic::Generator::new(project)
+            },
+            output,
+        ),
+        None => stitch(&cli),
+    }
+}
@@ -0,0 +1,596 @@
+use crate::analyzer::{Visitor, VisitorContext};
+use crate::nodes;
+use crate::render::{page_path, relative_href, resolve_refuri, Format};
+
+/// Directives rendered as block quotes headed by their name.
+const ADMONITIONS: &[&str] = &[
+    "note",
+    "tip",
+    "important",
+    "warning",
+    "caution",
+    "danger",
+    "example",
+    "see",
+    "seealso",
+];
+
+/// Escape text so that it renders literally: inline markup wherever it appears, and the
+/// markers of headings and list items at the start of a line.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            escaped.push('\n');
+        }
+
+        let content = line.trim_start_matches(' ');
+        escaped.push_str(&line[..line.len() - content.len()]);
+        let digits = content.len()
+            - content
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        let marker = if content.starts_with(['#', '-', '+']) {
+            Some(0)
+        } else if digits > 0 && content[digits..].starts_with(['.', ')']) {
+            Some(digits)
+        } else {
+            None
+        };
+
+        for (j, c) in content.char_indices() {
+            if Some(j) == marker
+                || matches!(
+                    c,
+                    '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '|' | '!'
+                )
+            {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Wrap text in a code span, using enough backticks that none inside can end it.
+fn code_span(text: &str) -> String {
+    let longest_run = text
+        .split(|c| c != '`')
+        .map(|run| run.len())
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest_run + 1);
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{fence} {text} {fence}")
+    } else {
+        format!("{fence}{text}{fence}")
+    }
+}
+
+/// Prefix the first line of a block with one string and its remaining lines with another,
+/// e.g. to nest a block within a list item.
+fn prefix_lines(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let prefix = if i == 0 { first } else { rest };
+            if line.is_empty() {
+                prefix.trim_end().to_owned()
+            } else {
+                format!("{prefix}{line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn is_inline(data: &nodes::NodeData) -> bool {
+    matches!(
+        data,
+        nodes::NodeData::Text(_)
+            | nodes::NodeData::Literal(_)
+            | nodes::NodeData::Emphasis(_)
+            | nodes::NodeData::Strong(_)
+            | nodes::NodeData::Reference(_)
+            | nodes::NodeData::NamedReference(_)
+            | nodes::NodeData::Role(_)
+            | nodes::NodeData::RefRole(_)
+            | nodes::NodeData::SubstitutionReference(_)
+            | nodes::NodeData::FootnoteReference(_)
+            | nodes::NodeData::InlineTarget(_)
+    )
+}
+
+/// Whether a node's children lie within a paragraph, even if the node itself does not.
+fn holds_inlines(data: &nodes::NodeData) -> bool {
+    matches!(
+        data,
+        nodes::NodeData::Heading(_)
+            | nodes::NodeData::Paragraph(_)
+            | nodes::NodeData::LineBlock(_)
+            | nodes::NodeData::Line(_)
+    )
+}
+
+/// A node rendered into Markdown, held until its parent is rendered.
+struct Rendered {
+    markdown: String,
+
+    /// Whether the node flows within a paragraph, rather than standing as a block
+    inline: bool,
+
+    /// For a list, each of its items; for a list item, the items of the first list within
+    /// it. List-tables are built from these.
+    items: Vec<Rendered>,
+}
+
+impl Rendered {
+    fn new(markdown: String, inline: bool) -> Self {
+        Self {
+            markdown,
+            inline,
+            items: vec![],
+        }
+    }
+}
+
+/// Concatenate nodes rendered within a paragraph.
+fn inlines(rendered: &[Rendered]) -> String {
+    rendered.iter().map(|node| node.markdown.as_str()).collect()
+}
+
+/// Join a sequence of rendered nodes as blocks separated by blank lines. Runs of inline
+/// nodes are treated as paragraphs.
+fn blocks(rendered: &[Rendered]) -> String {
+    let mut blocks: Vec<String> = vec![];
+    let mut paragraph = String::new();
+    for node in rendered {
+        if node.inline {
+            paragraph += &node.markdown;
+            continue;
+        }
+
+        if !paragraph.trim().is_empty() {
+            blocks.push(std::mem::take(&mut paragraph).trim().to_owned());
+        }
+        if !node.markdown.trim().is_empty() {
+            blocks.push(node.markdown.to_owned());
+        }
+    }
+
+    if !paragraph.trim().is_empty() {
+        blocks.push(paragraph.trim().to_owned());
+    }
+
+    blocks.join("\n\n")
+}
+
+/// The renderings of a node's children so far, and whether they lie within a paragraph.
+struct Frame {
+    children: Vec<Rendered>,
+    inline: bool,
+}
+
+/// Renders a document's AST into CommonMark, with pipe tables. Each node is rendered as the
+/// traversal leaves it, from the renderings of its children.
+struct MarkdownWriter<'p> {
+    page: &'p str,
+
+    /// A frame for each node being traversed, below one for the nodes the traversal starts at
+    stack: Vec<Frame>,
+}
+
+impl<'p> MarkdownWriter<'p> {
+    /// Create a writer for nodes which lie within a paragraph if `inline` is set.
+    fn new(page: &'p str, inline: bool) -> Self {
+        Self {
+            page,
+            stack: vec![Frame {
+                children: vec![],
+                inline,
+            }],
+        }
+    }
+
+    /// Render nodes which are not part of the tree, such as a directive's argument, within
+    /// a paragraph.
+    fn render_inlines(&self, nodes: &[nodes::Node]) -> String {
+        let mut writer = MarkdownWriter::new(self.page, true);
+        for node in nodes {
+            node.visit(&mut writer);
+        }
+        inlines(&writer.finish())
+    }
+
+    fn finish(mut self) -> Vec<Rendered> {
+        self.stack.pop().unwrap().children
+    }
+
+    fn href(&self, path: &str, fragment: Option<&str>) -> String {
+        relative_href(self.page, path, fragment, Format::Markdown)
+    }
+
+    fn inline(&self, node: &nodes::Node, children: Vec<Rendered>) -> String {
+        match &node.data {
+            nodes::NodeData::Text(text) => escape_markdown(&text.value),
+            nodes::NodeData::Literal(_) => code_span(&node.get_text()),
+            nodes::NodeData::Emphasis(_) => format!("*{}*", inlines(&children)),
+            nodes::NodeData::Strong(_) => format!("**{}**", inlines(&children)),
+            nodes::NodeData::Reference(reference) => {
+                let href = resolve_refuri(self.page, &reference.refuri, Format::Markdown);
+                let text = match inlines(&children) {
+                    text if text.is_empty() => escape_markdown(&reference.refuri),
+                    text => text,
+                };
+                format!("[{text}]({href})")
+            }
+            nodes::NodeData::Role(role) if children.is_empty() => code_span(&role.target),
+            nodes::NodeData::RefRole(refrole) => {
+                let text = match inlines(&children) {
+                    text if text.is_empty() => code_span(&refrole.role.target),
+                    text => text,
+                };
+
+                match (&refrole.fileid, &refrole.url) {
+                    (Some((page, html_id)), _) => {
+                        let fragment = Some(html_id.as_str()).filter(|id| !id.is_empty());
+                        format!("[{}]({})", text, self.href(page, fragment))
+                    }
+                    (None, Some(url)) => format!("[{text}]({url})"),
+                    (None, None) => text,
+                }
+            }
+            nodes::NodeData::FootnoteReference(reference) => format!("[^{}]", reference.id),
+            nodes::NodeData::Comment(_)
+            | nodes::NodeData::NamedReference(_)
+            | nodes::NodeData::InlineTarget(_)
+            | nodes::NodeData::TargetIdentifier(_)
+            | nodes::NodeData::SubstitutionDefinition(_) => String::new(),
+            _ => inlines(&children),
+        }
+    }
+
+    fn code(&self, code: &nodes::Code) -> String {
+        let longest_run = code
+            .value
+            .split(|c| c != '`')
+            .map(|run| run.len())
+            .max()
+            .unwrap_or(0);
+        let fence = "`".repeat(std::cmp::max(3, longest_run + 1));
+
+        let mut out = String::new();
+        if let Some(caption) = &code.caption {
+            out += &format!("*{}*\n\n", escape_markdown(caption));
+        }
+        out += &format!(
+            "{}{}\n{}\n{}",
+            fence,
+            code.lang.as_deref().unwrap_or(""),
+            code.value.trim_end_matches('\n'),
+            fence
+        );
+        out
+    }
+
+    /// Render a list from its rendered items, keeping them for any list-table it is part of.
+    fn list(&self, node: &nodes::Node, list: &nodes::List, children: Vec<Rendered>) -> Rendered {
+        let items: Vec<Rendered> = node
+            .data
+            .children()
+            .iter()
+            .zip(children)
+            .filter(|(child, _)| matches!(child.data, nodes::NodeData::ListItem(_)))
+            .map(|(_, item)| item)
+            .collect();
+
+        let mut number = list.startat.unwrap_or(1);
+        let mut lines = vec![];
+        for item in &items {
+            let marker = match list.enumtype {
+                nodes::ListEnumType::Unordered => "- ".to_owned(),
+                // Markdown only has numbered lists, so lettered and roman lists are numbered
+                _ => {
+                    number += 1;
+                    format!("{}. ", number - 1)
+                }
+            };
+
+            lines.push(prefix_lines(
+                &item.markdown,
+                &marker,
+                &" ".repeat(marker.len()),
+            ));
+        }
+
+        Rendered {
+            markdown: lines.join("\n"),
+            inline: false,
+            items,
+        }
+    }
+
+    /// Render a list-table directive as a pipe table, taking the first row as the header.
+    fn table(&self, node: &nodes::Node, children: Vec<Rendered>) -> String {
+        let rows: Vec<Vec<String>> = match first_list(node, &children) {
+            Some(rows) => rows
+                .items
+                .iter()
+                .map(|row| {
+                    row.items
+                        .iter()
+                        .map(|cell| {
+                            cell.markdown
+                                .replace('|', "\\|")
+                                .lines()
+                                .filter(|line| !line.trim().is_empty())
+                                .collect::<Vec<_>>()
+                                .join("<br>")
+                        })
+                        .collect()
+                })
+                .collect(),
+            None => return blocks(&children),
+        };
+
+        let n_columns = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        if n_columns == 0 {
+            return String::new();
+        }
+
+        let format_row = |row: &[String]| {
+            let mut cells: Vec<&str> = row.iter().map(|cell| cell.as_str()).collect();
+            cells.resize(n_columns, "");
+            format!("| {} |", cells.join(" | "))
+        };
+
+        let mut lines = vec![format_row(&rows[0])];
+        lines.push(format!("|{}", " --- |".repeat(n_columns)));
+        lines.extend(rows[1..].iter().map(|row| format_row(row)));
+        lines.join("\n")
+    }
+
+    fn directive(
+        &self,
+        node: &nodes::Node,
+        directive: &nodes::Directive,
+        children: Vec<Rendered>,
+    ) -> String {
+        if let Some(entries) = &directive.entries {
+            return entries
+                .iter()
+                .filter_map(|entry| {
+                    let href = match (&entry.slug, &entry.url) {
+                        (Some(slug), _) => self.href(slug, None),
+                        (None, Some(url)) => url.to_owned(),
+                        (None, None) => return None,
+                    };
+                    let title = entry.title.as_deref().unwrap_or(href.as_str());
+                    Some(format!("- [{}]({})", escape_markdown(title), href))
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+
+        if directive.name == "list-table" {
+            return self.table(node, children);
+        }
+
+        if ADMONITIONS.contains(&directive.name.as_str()) {
+            let mut title = format!("**{}**", directive.name.to_uppercase());
+            if !directive.argument.is_empty() {
+                title += &format!(" {}", self.render_inlines(&directive.argument));
+            }
+
+            let body = blocks(&children);
+            let content = if body.is_empty() {
+                title
+            } else {
+                format!("{title}\n\n{body}")
+            };
+            return prefix_lines(&content, "> ", "> ");
+        }
+
+        blocks(&children)
+    }
+
+    fn block(
+        &self,
+        context: &VisitorContext,
+        node: &nodes::Node,
+        children: Vec<Rendered>,
+    ) -> Rendered {
+        let markdown = match &node.data {
+            nodes::NodeData::Heading(_) => {
+                let section_depth = context
+                    .ancestors()
+                    .iter()
+                    .filter(|ancestor| matches!(ancestor.data, nodes::NodeData::Section(_)))
+                    .count();
+                format!(
+                    "{} {}",
+                    "#".repeat(section_depth.clamp(1, 6)),
+                    inlines(&children)
+                )
+            }
+            nodes::NodeData::Paragraph(_) => inlines(&children).trim().to_owned(),
+            nodes::NodeData::Code(code) => self.code(code),
+            nodes::NodeData::List(list) => return self.list(node, list, children),
+            nodes::NodeData::ListItem(_) => {
+                let markdown = blocks(&children);
+                let items = first_list_items(node, children);
+                return Rendered {
+                    markdown,
+                    inline: false,
+                    items,
+                };
+            }
+            nodes::NodeData::DefinitionList(_) => children
+                .iter()
+                .map(|item| item.markdown.as_str())
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            nodes::NodeData::DefinitionListItem(item) => {
+                let definition = blocks(&children);
+                format!(
+                    "{}\n{}",
+                    self.render_inlines(&item.term),
+                    prefix_lines(&definition, ": ", "  ")
+                )
+            }
+            nodes::NodeData::FieldList(_) => children
+                .iter()
+                .map(|field| field.markdown.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            nodes::NodeData::Field(field) => {
+                let name = field.label.as_deref().unwrap_or(&field.name);
+                let body = blocks(&children);
+                prefix_lines(&body, &format!("- **{}**: ", escape_markdown(name)), "  ")
+            }
+            nodes::NodeData::LineBlock(_) => children
+                .iter()
+                .map(|line| line.markdown.as_str())
+                .collect::<Vec<_>>()
+                .join("  \n"),
+            nodes::NodeData::Directive(directive) => self.directive(node, directive, children),
+            nodes::NodeData::Target(target) => {
+                let body = blocks(&children);
+                match &target.html_id {
+                    Some(html_id) if body.is_empty() => format!("<a id=\"{html_id}\"></a>"),
+                    Some(html_id) => format!("<a id=\"{html_id}\"></a>\n\n{body}"),
+                    None => body,
+                }
+            }
+            nodes::NodeData::Footnote(footnote) => {
+                let body = blocks(&children);
+                prefix_lines(&body, &format!("[^{}]: ", footnote.id), "    ")
+            }
+            nodes::NodeData::Transition(_) => "---".to_owned(),
+            nodes::NodeData::Comment(_)
+            | nodes::NodeData::SubstitutionDefinition(_)
+            | nodes::NodeData::TargetIdentifier(_) => String::new(),
+            _ => blocks(&children),
+        };
+
+        Rendered::new(markdown, false)
+    }
+}
+
+/// The rendering of the first list among a node's children.
+fn first_list<'r>(node: &nodes::Node, children: &'r [Rendered]) -> Option<&'r Rendered> {
+    node.data
+        .children()
+        .iter()
+        .zip(children)
+        .find(|(child, _)| matches!(child.data, nodes::NodeData::List(_)))
+        .map(|(_, list)| list)
+}
+
+/// Take the items of the first list among a node's children.
+fn first_list_items(node: &nodes::Node, children: Vec<Rendered>) -> Vec<Rendered> {
+    node.data
+        .children()
+        .iter()
+        .zip(children)
+        .find(|(child, _)| matches!(child.data, nodes::NodeData::List(_)))
+        .map(|(_, list)| list.items)
+        .unwrap_or_default()
+}
+
+impl<'a, 'p> Visitor<'a> for MarkdownWriter<'p> {
+    fn enter_node(&mut self, _context: &VisitorContext<'a>, node: &'a nodes::Node) {
+        let inline = self.stack.last().unwrap().inline || is_inline(&node.data);
+        self.stack.push(Frame {
+            children: vec![],
+            inline: inline || holds_inlines(&node.data),
+        });
+    }
+
+    fn exit_node(&mut self, context: &VisitorContext<'a>, node: &'a nodes::Node) {
+        let children = self.stack.pop().unwrap().children;
+        let parent = self.stack.last().unwrap();
+        let rendered = if parent.inline || is_inline(&node.data) {
+            Rendered::new(self.inline(node, children), true)
+        } else {
+            self.block(context, node, children)
+        };
+        self.stack.last_mut().unwrap().children.push(rendered);
+    }
+}
+
+/// Render a stitched document as a Markdown file with YAML front matter.
+pub fn render_document(document: &nodes::Document) -> String {
+    let page = page_path(document);
+    let mut writer = MarkdownWriter::new(&page, false);
+    document.ast.visit(&mut writer);
+    let body = blocks(&writer.finish());
+
+    // JSON strings and arrays are also valid YAML, and spare us quoting rules
+    let facets = document.facets.to_owned().unwrap_or_default();
+    format!(
+        "---\npage_id: {}\ntitle: {}\nfacets: {}\n---\n\n{}\n",
+        serde_json::Value::from(document.page_id.as_str()),
+        serde_json::Value::from(document.title().unwrap_or_default()),
+        serde_json::to_string(&facets).unwrap(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn render() {
+        let markdown = render_document(&nodes::tests::load_sample());
+        let (front_matter, body) = markdown
+            .strip_prefix("---\n")
+            .unwrap()
+            .split_once("\n---\n\n")
+            .unwrap();
+
+        let front_matter: Vec<&str> = front_matter.lines().collect();
+        assert_eq!(
+            front_matter[..2],
+            [
+                "page_id: \"bi-connector/heli/master/supported-operations\"",
+                "title: \"Supported SQL Functions and Operators\""
+            ]
+        );
+        assert!(front_matter[2].starts_with("facets: [{\"category\":\"target_product\""));
+
+        assert!(body.starts_with("# Supported SQL Functions and Operators\n\n"));
+        assert!(body.contains("\n\n## Comparison Functions and Operators\n\n"));
+
+        // Each list-table becomes a pipe table with a header row
+        assert_eq!(body.matches("\n| --- |").count(), 13);
+
+        // Links to other pages point at their Markdown files
+        assert!(body.contains("(reference/type-conversion.md#std-label-type-conversion-modes)"));
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(
+            prefix_lines("first\n\nsecond", "- ", "  "),
+            "- first\n\n  second"
+        );
+        assert_eq!(code_span("a`b"), "``a`b``");
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(
+            escape_markdown("snake_case | ![alt] *em*"),
+            "snake\\_case \\| \\!\\[alt\\] \\*em\\*"
+        );
+        assert_eq!(
+            escape_markdown("# one\n- two\n  + three\n4. four\n5) five\na-b 6. c"),
+            "\\# one\n\\- two\n  \\+ three\n4\\. four\n5\\) five\na-b 6. c"
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, path::PathBuf};
 
-use crate::analyzer::{self, FileIdStack};
+use crate::analyzer;
 
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
@@ -10,16 +10,22 @@ lazy_static! {
         regex::Regex::new(r###"\.((txt)|(rst)|(yaml)|(ast))$"###).unwrap();
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct SourceInfo {
     line: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Position {
     start: SourceInfo,
 }
 
+impl Position {
+    pub fn line(&self) -> i32 {
+        self.start.line
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum ListEnumType {
@@ -89,41 +95,182 @@ pub struct Node {
 }
 
 impl Node {
+    pub fn new(data: NodeData, position: Position) -> Self {
+        Self { data, position }
+    }
+
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
+
     pub fn for_each(&mut self, f: &mut impl FnMut(&mut Node)) {
         let mut analyzer = analyzer::SimpleAnalyzer::new(f);
         self.run_analyzer(&mut analyzer);
     }
 
+    /// Concatenate the text of this node and all of its descendants.
+    pub fn get_text(&self) -> String {
+        let mut result = String::new();
+        self.collect_text(&mut result);
+        result
+    }
+
+    fn collect_text(&self, out: &mut String) {
+        if let NodeData::Text(text) = &self.data {
+            out.push_str(&text.value);
+        }
+
+        for child in self.data.children() {
+            child.collect_text(out);
+        }
+    }
+
     pub fn run_analyzer(&mut self, analyzer: &mut impl analyzer::Analyzer) {
-        self.run_analyzer_inner(&mut analyzer::FileIdStack::new(), analyzer)
+        self.run_analyzer_inner(
+            &mut analyzer::AnalyzerContext::for_analyzer(analyzer),
+            analyzer,
+        )
+    }
+
+    pub(crate) fn run_analyzer_with_context(
+        &mut self,
+        context: &mut analyzer::AnalyzerContext,
+        analyzer: &mut impl analyzer::Analyzer,
+    ) {
+        self.run_analyzer_inner(context, analyzer)
+    }
+
+    /// Update the traversal context upon entering this node. Returns true if a fileid was
+    /// pushed which must be popped upon leaving it.
+    fn enter_context(&self, context: &mut analyzer::AnalyzerContext) -> bool {
+        match &self.data {
+            NodeData::Root(root_node) => {
+                context.fileid_stack.push(&root_node.fileid);
+                true
+            }
+            _ => false,
+        }
     }
 
     fn run_analyzer_inner(
         &mut self,
-        fileid_stack: &mut FileIdStack,
+        context: &mut analyzer::AnalyzerContext,
         analyzer: &mut impl analyzer::Analyzer,
+    ) {
+        let need_to_pop = self.enter_context(context);
+
+        analyzer.enter_node(context, self);
+
+        context.push_ancestor(self);
+        for child in self.data.get_children() {
+            child.run_analyzer_inner(context, analyzer);
+        }
+        context.pop_ancestor();
+
+        analyzer.exit_node(context, self);
+
+        if need_to_pop {
+            context.fileid_stack.pop();
+        }
+    }
+
+    /// Rewrite the descendants of this node. The node itself is never passed to the
+    /// transform, since it has no sibling list to be removed from or replaced within.
+    pub fn run_transform(&mut self, transform: &mut impl analyzer::Transform) {
+        let mut context = analyzer::AnalyzerContext::new();
+        let need_to_pop = self.enter_context(&mut context);
+        self.transform_children(&mut context, transform);
+        if need_to_pop {
+            context.fileid_stack.pop();
+        }
+    }
+
+    fn transform_children(
+        &mut self,
+        context: &mut analyzer::AnalyzerContext,
+        transform: &mut impl analyzer::Transform,
+    ) {
+        if self.data.get_children_vec().is_none() {
+            return;
+        }
+
+        context.push_ancestor(self);
+        let children = self.data.get_children_vec().unwrap();
+        let siblings = std::mem::take(children);
+        Self::transform_siblings(context, transform, siblings, children);
+        context.pop_ancestor();
+    }
+
+    /// Transform a list of sibling nodes, appending whatever they become to `out`.
+    fn transform_siblings(
+        context: &mut analyzer::AnalyzerContext,
+        transform: &mut impl analyzer::Transform,
+        siblings: Vec<Node>,
+        out: &mut Vec<Node>,
+    ) {
+        for mut child in siblings {
+            let need_to_pop = child.enter_context(context);
+
+            match transform.transform_node(context, &mut child) {
+                analyzer::Action::Keep => {
+                    child.transform_children(context, transform);
+                    out.push(child);
+                }
+                analyzer::Action::Remove => (),
+                analyzer::Action::Replace(replacements) => out.extend(replacements),
+                analyzer::Action::Unwrap => match child.data.get_children_vec() {
+                    // Splice the node's children into its place, and transform them as if
+                    // they had been there all along. This happens before any fileid the
+                    // node pushed is popped, so an unwrapped Root still attributes them.
+                    Some(grandchildren) => {
+                        let grandchildren = std::mem::take(grandchildren);
+                        Self::transform_siblings(context, transform, grandchildren, out);
+                    }
+                    None => out.push(child),
+                },
+            }
+
+            if need_to_pop {
+                context.fileid_stack.pop();
+            }
+        }
+    }
+
+    /// Traverse this tree without modifying it.
+    pub fn visit<'a>(&'a self, visitor: &mut impl analyzer::Visitor<'a>) {
+        self.visit_inner(&mut analyzer::VisitorContext::new(), visitor)
+    }
+
+    fn visit_inner<'a>(
+        &'a self,
+        context: &mut analyzer::VisitorContext<'a>,
+        visitor: &mut impl analyzer::Visitor<'a>,
     ) {
         let need_to_pop = if let NodeData::Root(root_node) = &self.data {
-            fileid_stack.push(&root_node.fileid);
+            context.push_fileid(&root_node.fileid);
             true
         } else {
             false
         };
 
-        analyzer.enter_node(fileid_stack, self);
+        visitor.enter_node(context, self);
 
-        for child in self.data.get_children() {
-            child.run_analyzer_inner(fileid_stack, analyzer);
+        context.push_ancestor(self);
+        for child in self.data.children() {
+            child.visit_inner(context, visitor);
         }
+        context.pop_ancestor();
 
-        analyzer.exit_node(fileid_stack, self);
+        visitor.exit_node(context, self);
 
         if need_to_pop {
-            fileid_stack.pop();
+            context.pop_fileid();
         }
     }
 }
 
+/// The contents of a node, by its type. Each keeps any fields it does not know in an `extra`
+/// map, which is written back out untouched, as [`Document`] does for the page itself.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
@@ -137,6 +284,7 @@ pub enum NodeData {
     FootnoteReference(FootnoteReference),
     SubstitutionDefinition(SubstitutionDefinition),
     SubstitutionReference(SubstitutionReference),
+    BlockSubstitutionReference(BlockSubstitutionReference),
     Root(Root),
     Heading(Heading),
 
@@ -172,6 +320,46 @@ pub enum NodeData {
 }
 
 impl NodeData {
+    /// The name of this node's type, as it appears in the serialized AST.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            NodeData::Code(_) => "code",
+            NodeData::Comment(_) => "comment",
+            NodeData::Label(_) => "label",
+            NodeData::Section(_) => "section",
+            NodeData::Paragraph(_) => "paragraph",
+            NodeData::Footnote(_) => "footnote",
+            NodeData::FootnoteReference(_) => "footnote_reference",
+            NodeData::SubstitutionDefinition(_) => "substitution_definition",
+            NodeData::SubstitutionReference(_) => "substitution_reference",
+            NodeData::BlockSubstitutionReference(_) => "block_substitution_reference",
+            NodeData::Root(_) => "root",
+            NodeData::Heading(_) => "heading",
+            NodeData::DefinitionListItem(_) => "definitionListItem",
+            NodeData::DefinitionList(_) => "definitionList",
+            NodeData::ListItem(_) => "listItem",
+            NodeData::List(_) => "list",
+            NodeData::Line(_) => "line",
+            NodeData::LineBlock(_) => "line_block",
+            NodeData::Directive(_) => "directive",
+            NodeData::DirectiveArgument(_) => "directive_argument",
+            NodeData::Target(_) => "target",
+            NodeData::TargetIdentifier(_) => "target_identifier",
+            NodeData::InlineTarget(_) => "inline_target",
+            NodeData::Reference(_) => "reference",
+            NodeData::NamedReference(_) => "named_reference",
+            NodeData::Role(_) => "role",
+            NodeData::RefRole(_) => "ref_role",
+            NodeData::Text(_) => "text",
+            NodeData::Literal(_) => "literal",
+            NodeData::Emphasis(_) => "emphasis",
+            NodeData::Strong(_) => "strong",
+            NodeData::Field(_) => "field",
+            NodeData::FieldList(_) => "field_list",
+            NodeData::Transition(_) => "transition",
+        }
+    }
+
     pub fn get_children(&mut self) -> &mut [Node] {
         match self {
             NodeData::Code(_) => &mut [],
@@ -183,6 +371,7 @@ impl NodeData {
             NodeData::FootnoteReference(n) => &mut n.children,
             NodeData::SubstitutionDefinition(n) => &mut n.children,
             NodeData::SubstitutionReference(n) => &mut n.children,
+            NodeData::BlockSubstitutionReference(n) => &mut n.children,
             NodeData::Root(n) => &mut n.children,
             NodeData::Heading(n) => &mut n.children,
             NodeData::DefinitionListItem(n) => &mut n.children,
@@ -209,76 +398,205 @@ impl NodeData {
             NodeData::Transition(_) => &mut [],
         }
     }
+
+    /// Like [`NodeData::get_children`], but allows adding and removing children.
+    pub fn get_children_vec(&mut self) -> Option<&mut Vec<Node>> {
+        match self {
+            NodeData::Code(_) => None,
+            NodeData::Comment(n) => Some(&mut n.children),
+            NodeData::Label(n) => Some(&mut n.children),
+            NodeData::Section(n) => Some(&mut n.children),
+            NodeData::Paragraph(n) => Some(&mut n.children),
+            NodeData::Footnote(n) => Some(&mut n.children),
+            NodeData::FootnoteReference(n) => Some(&mut n.children),
+            NodeData::SubstitutionDefinition(n) => Some(&mut n.children),
+            NodeData::SubstitutionReference(n) => Some(&mut n.children),
+            NodeData::BlockSubstitutionReference(n) => Some(&mut n.children),
+            NodeData::Root(n) => Some(&mut n.children),
+            NodeData::Heading(n) => Some(&mut n.children),
+            NodeData::DefinitionListItem(n) => Some(&mut n.children),
+            NodeData::DefinitionList(n) => Some(&mut n.children),
+            NodeData::ListItem(n) => Some(&mut n.children),
+            NodeData::List(n) => Some(&mut n.children),
+            NodeData::Line(n) => Some(&mut n.children),
+            NodeData::LineBlock(n) => Some(&mut n.children),
+            NodeData::Directive(n) => Some(&mut n.children),
+            NodeData::DirectiveArgument(n) => Some(&mut n.children),
+            NodeData::Target(n) => Some(&mut n.children),
+            NodeData::TargetIdentifier(n) => Some(&mut n.children),
+            NodeData::InlineTarget(_) => None,
+            NodeData::Reference(n) => Some(&mut n.children),
+            NodeData::NamedReference(_) => None,
+            NodeData::Role(n) => Some(&mut n.children),
+            NodeData::RefRole(_) => None,
+            NodeData::Text(_) => None,
+            NodeData::Literal(n) => Some(&mut n.children),
+            NodeData::Emphasis(n) => Some(&mut n.children),
+            NodeData::Strong(n) => Some(&mut n.children),
+            NodeData::Field(n) => Some(&mut n.children),
+            NodeData::FieldList(n) => Some(&mut n.children),
+            NodeData::Transition(_) => None,
+        }
+    }
+
+    pub fn children(&self) -> &[Node] {
+        match self {
+            NodeData::Code(_) => &[],
+            NodeData::Comment(n) => &n.children,
+            NodeData::Label(n) => &n.children,
+            NodeData::Section(n) => &n.children,
+            NodeData::Paragraph(n) => &n.children,
+            NodeData::Footnote(n) => &n.children,
+            NodeData::FootnoteReference(n) => &n.children,
+            NodeData::SubstitutionDefinition(n) => &n.children,
+            NodeData::SubstitutionReference(n) => &n.children,
+            NodeData::BlockSubstitutionReference(n) => &n.children,
+            NodeData::Root(n) => &n.children,
+            NodeData::Heading(n) => &n.children,
+            NodeData::DefinitionListItem(n) => &n.children,
+            NodeData::DefinitionList(n) => &n.children,
+            NodeData::ListItem(n) => &n.children,
+            NodeData::List(n) => &n.children,
+            NodeData::Line(n) => &n.children,
+            NodeData::LineBlock(n) => &n.children,
+            NodeData::Directive(n) => &n.children,
+            NodeData::DirectiveArgument(n) => &n.children,
+            NodeData::Target(n) => &n.children,
+            NodeData::TargetIdentifier(n) => &n.children,
+            NodeData::InlineTarget(_) => &[],
+            NodeData::Reference(n) => &n.children,
+            NodeData::NamedReference(_) => &[],
+            NodeData::Role(n) => &n.children,
+            NodeData::RefRole(_) => &[],
+            NodeData::Text(_) => &[],
+            NodeData::Literal(n) => &n.children,
+            NodeData::Emphasis(n) => &n.children,
+            NodeData::Strong(n) => &n.children,
+            NodeData::Field(n) => &n.children,
+            NodeData::FieldList(n) => &n.children,
+            NodeData::Transition(_) => &[],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Code {
-    lang: Option<String>,
+    pub lang: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    caption: Option<String>,
+    pub caption: Option<String>,
     copyable: bool,
-    emphasize_lines: Option<Vec<(i32, i32)>>,
-    value: String,
-    linenos: bool,
+    pub emphasize_lines: Option<Vec<(i32, i32)>>,
+    pub value: String,
+    pub linenos: bool,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    lineno_start: Option<i32>,
+    pub lineno_start: Option<i32>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     source: Option<String>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Comment {
     children: Vec<Node>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Label {
     children: Vec<Node>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Section {
     children: Vec<Node>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+#[cfg(any(test, feature = "synthetic"))]
+impl Section {
+    pub fn new(children: Vec<Node>) -> Self {
+        Self {
+            children,
+            extra: bson::Document::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Paragraph {
     children: Vec<Node>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+#[cfg(any(test, feature = "synthetic"))]
+impl Paragraph {
+    pub fn new(children: Vec<Node>) -> Self {
+        Self {
+            children,
+            extra: bson::Document::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Footnote {
     children: Vec<Node>,
-    id: String,
+    pub id: String,
     name: Option<String>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FootnoteReference {
     children: Vec<Node>, // InlineNode
-    id: String,
+    pub id: String,
     refname: Option<String>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SubstitutionDefinition {
-    children: Vec<Node>, // InlineNode
-    name: String,
+    pub children: Vec<Node>, // InlineNode
+    pub name: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SubstitutionReference {
-    children: Vec<Node>, // InlineNode
-    name: String,
+    pub children: Vec<Node>, // InlineNode
+    pub name: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BlockSubstitutionReference {
-    children: Vec<Node>,
-    name: String,
+    pub children: Vec<Node>,
+    pub name: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -287,79 +605,142 @@ pub struct Root {
     pub fileid: FileId,
 
     #[serde(default)]
-    options: HashMap<String, bson::Bson>,
+    pub options: HashMap<String, bson::Bson>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+#[cfg(any(test, feature = "synthetic"))]
+impl Root {
+    pub fn new(fileid: FileId, children: Vec<Node>) -> Self {
+        Self {
+            children,
+            fileid,
+            options: HashMap::new(),
+            extra: bson::Document::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Heading {
     children: Vec<Node>, // InlineNode
-    id: String,
+    pub id: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+#[cfg(any(test, feature = "synthetic"))]
+impl Heading {
+    pub fn new(id: impl Into<String>, children: Vec<Node>) -> Self {
+        Self {
+            children,
+            id: id.into(),
+            extra: bson::Document::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DefinitionListItem {
     children: Vec<Node>,
-    term: Vec<Node>, // InlineNode
+    pub term: Vec<Node>, // InlineNode
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DefinitionList {
     children: Vec<Node>, // DefinitionListItem
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ListItem {
     children: Vec<Node>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct List {
     children: Vec<Node>, // ListItem
-    enumtype: ListEnumType,
+    pub enumtype: ListEnumType,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    startat: Option<i32>,
+    pub startat: Option<i32>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Line {
     children: Vec<Node>, // InlineNode
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LineBlock {
     children: Vec<Node>, // Line
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Directive {
     children: Vec<Node>,
-    domain: String,
-    name: String,
-    argument: Vec<Node>, // InlineNode
+    pub domain: String,
+    pub name: String,
+    pub argument: Vec<Node>, // InlineNode
 
     #[serde(default)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
-    options: HashMap<String, bson::Bson>,
+    pub options: HashMap<String, bson::Bson>,
+
+    /// Only present on toctree directives
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<TocTreeDirectiveEntry>>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TocTreeDirectiveEntry {
-    title: Option<String>,
-    url: Option<String>,
-    slug: Option<String>,
-    ref_project: Option<String>,
-}
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct TocTreeDirective {
-    directive: Directive,
-    entries: Vec<Node>, // TocTreeDirectiveEntry
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ref_project: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DirectiveArgument {
     children: Vec<Node>, // InlineNode
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -372,91 +753,185 @@ pub struct Target {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     options: Option<HashMap<String, bson::Bson>>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+#[cfg(any(test, feature = "synthetic"))]
+impl Target {
+    pub fn new(domain: impl Into<String>, name: impl Into<String>, children: Vec<Node>) -> Self {
+        Self {
+            children,
+            domain: domain.into(),
+            name: name.into(),
+            html_id: None,
+            options: None,
+            extra: bson::Document::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TargetIdentifier {
     pub children: Vec<Node>, // InlineNode
     pub ids: Vec<String>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+#[cfg(any(test, feature = "synthetic"))]
+impl TargetIdentifier {
+    pub fn new(ids: Vec<String>, children: Vec<Node>) -> Self {
+        Self {
+            children,
+            ids,
+            extra: bson::Document::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InlineTarget {
     #[serde(flatten)]
-    target: Target,
+    pub target: Target,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Reference {
     children: Vec<Node>, // InlineNode
-    refuri: String,
+    pub refuri: String,
 
     #[serde(default)]
     #[serde(skip_serializing_if = "String::is_empty")]
     refname: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NamedReference {
     refname: String,
-    refuri: String,
+    pub refuri: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Role {
     children: Vec<Node>, // InlineNode
-    domain: String,
-    name: String,
-    target: String,
+    pub domain: String,
+    pub name: String,
+    pub target: String,
     flag: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RefRole {
     #[serde(flatten)]
-    role: Role,
+    pub role: Role,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fileid: Option<(String, String)>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    url: Option<String>,
+    pub url: Option<String>,
+}
+
+#[cfg(any(test, feature = "synthetic"))]
+impl RefRole {
+    /// A reference to a target, resolved to the page and html id given by `fileid`.
+    pub fn new(
+        domain: impl Into<String>,
+        name: impl Into<String>,
+        target: impl Into<String>,
+        fileid: Option<(String, String)>,
+    ) -> Self {
+        Self {
+            role: Role {
+                children: vec![],
+                domain: domain.into(),
+                name: name.into(),
+                target: target.into(),
+                flag: String::new(),
+                extra: bson::Document::new(),
+            },
+            fileid,
+            url: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Text {
-    value: String,
+    pub value: String,
+
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+impl Text {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            extra: bson::Document::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Literal {
     children: Vec<Node>, // InlineNode
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Emphasis {
     children: Vec<Node>, // InlineNode
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Strong {
     children: Vec<Node>, // InlineNode
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Field {
     children: Vec<Node>,
-    name: String,
-    label: Option<String>,
+    pub name: String,
+    pub label: Option<String>,
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FieldList {
     children: Vec<Node>, // Field
+
+    #[serde(flatten)]
+    extra: bson::Document,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Transition {}
+pub struct Transition {
+    #[serde(flatten)]
+    extra: bson::Document,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StaticAssetReference {
@@ -464,12 +939,22 @@ pub struct StaticAssetReference {
     key: String,
 }
 
+#[cfg(any(test, feature = "synthetic"))]
+impl StaticAssetReference {
+    pub fn new(checksum: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            checksum: checksum.into(),
+            key: key.into(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Facet {
-    category: String,
-    value: String,
-    sub_facets: Option<Vec<Facet>>,
-    display_name: String,
+    pub category: String,
+    pub value: String,
+    pub sub_facets: Option<Vec<Facet>>,
+    pub display_name: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -479,16 +964,88 @@ pub struct Document {
     pub ast: Node,
     source: String,
     static_assets: Vec<StaticAssetReference>,
-    facets: Option<Vec<Facet>>,
+    pub facets: Option<Vec<Facet>>,
+
+    /// Any other fields, which stitcher passes through untouched
+    #[serde(flatten)]
+    extra: bson::Document,
+}
+
+impl Document {
+    #[cfg(any(test, feature = "synthetic"))]
+    pub fn new(
+        page_id: impl Into<String>,
+        filename: FileId,
+        ast: Node,
+        static_assets: Vec<StaticAssetReference>,
+    ) -> Self {
+        Self {
+            page_id: page_id.into(),
+            filename,
+            ast,
+            source: String::new(),
+            static_assets,
+            facets: None,
+            extra: bson::Document::new(),
+        }
+    }
+
+    /// The text of the page's first heading, if it has one.
+    pub fn title(&self) -> Option<String> {
+        fn first_heading(node: &Node) -> Option<String> {
+            if let NodeData::Heading(_) = node.data {
+                return Some(node.get_text());
+            }
+
+            node.data.children().iter().find_map(first_heading)
+        }
+
+        first_heading(&self.ast)
+    }
+
+    /// Run an analyzer over this page, calling its page-level hooks before and after
+    /// traversing the page's tree. Included files appear within the tree as nested Root
+    /// nodes, and are attributed to this page.
+    pub fn run_analyzer(&mut self, analyzer: &mut impl analyzer::Analyzer) {
+        let mut context = analyzer::AnalyzerContext::for_analyzer(analyzer);
+        analyzer.enter_page(&context, self);
+        self.ast.run_analyzer_with_context(&mut context, analyzer);
+        analyzer.exit_page(&context, self);
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use pretty_assertions::assert_eq;
     use std::io::Seek;
 
     use super::*;
 
+    /// The sample page which most tests work on.
+    pub(crate) fn load_sample() -> Document {
+        let f = std::fs::File::open("test_data/supported-operations.bson").unwrap();
+        bson::from_reader(std::io::BufReader::new(f)).unwrap()
+    }
+
+    /// A label target with a single id, as the parser writes it.
+    pub(crate) fn make_target(line: i32, id: &str) -> bson::Bson {
+        bson::bson!({
+            "type": "target",
+            "position": {"start": {"line": line}},
+            "domain": "std",
+            "name": "label",
+            "html_id": null,
+            "children": [
+                {
+                    "type": "target_identifier",
+                    "position": {"start": {"line": line}},
+                    "ids": [id],
+                    "children": []
+                }
+            ]
+        })
+    }
+
     fn normalize_bson(value: &mut bson::Bson) {
         if let bson::Bson::Document(map) = value {
             let mut sorted_map: bson::Document = bson::Document::new();
@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Somewhere to write a stitched bundle: a sequence of files, each started with
+/// [`OutputSink::start_file`] and then written through [`Write`].
+pub trait OutputSink: Write + Send {
+    /// Begin a new file at a path relative to the root of the output, such as
+    /// "documents/index.bson". Subsequent writes go to this file.
+    fn start_file(&mut self, path: &str) -> Result<()>;
+
+    /// Flush everything to its destination.
+    fn finish(self) -> Result<()>;
+}
+
+/// Write the stitched bundle as an uncompressed zip archive.
+pub struct ZipSink {
+    archive: zip::ZipWriter<BufWriter<File>>,
+}
+
+impl ZipSink {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Error creating output: {}", path.display()))?;
+        Ok(Self {
+            archive: zip::ZipWriter::new(BufWriter::new(file)),
+        })
+    }
+}
+
+impl Write for ZipSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.archive.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.archive.flush()
+    }
+}
+
+impl OutputSink for ZipSink {
+    fn start_file(&mut self, path: &str) -> Result<()> {
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        self.archive.start_file(path, options)?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        self.archive.finish()?.flush()?;
+        Ok(())
+    }
+}
+
+/// Write the stitched bundle as a tree of plain files, laid out as in the zip archive.
+pub struct DirectorySink {
+    root: PathBuf,
+    current: Option<BufWriter<File>>,
+}
+
+impl DirectorySink {
+    /// Write into a directory, creating it if needed. An existing directory must be empty,
+    /// so that the output is never mixed with files left over from something else.
+    pub fn create(root: &Path) -> Result<Self> {
+        if root.exists() {
+            if !root.is_dir() {
+                bail!("Output is not a directory: {}", root.display());
+            }
+            if std::fs::read_dir(root)
+                .with_context(|| format!("Error reading output: {}", root.display()))?
+                .next()
+                .is_some()
+            {
+                bail!("Output directory is not empty: {}", root.display());
+            }
+        }
+
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("Error creating output: {}", root.display()))?;
+        Ok(Self {
+            root: root.to_owned(),
+            current: None,
+        })
+    }
+}
+
+impl Write for DirectorySink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.current {
+            Some(file) => file.write(buf),
+            None => Err(std::io::Error::other("No output file has been started")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.current {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Join a relative path from an untrusted source, such as a bundle, onto a directory. Fails
+/// if the path is absolute or has any component, such as "..", which could lead outside it.
+pub fn join_within(root: &Path, relative: impl AsRef<Path>) -> Result<PathBuf> {
+    let relative = relative.as_ref();
+    if relative
+        .components()
+        .any(|component| !matches!(component, std::path::Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "Refusing to write outside of the output: {}",
+            relative.display()
+        ));
+    }
+
+    Ok(root.join(relative))
+}
+
+impl OutputSink for DirectorySink {
+    fn start_file(&mut self, path: &str) -> Result<()> {
+        self.flush()?;
+
+        let full_path = join_within(&self.root, path)?;
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = File::create(&full_path)
+            .with_context(|| format!("Error creating {}", full_path.display()))?;
+        self.current = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("output");
+        let mut sink = DirectorySink::create(&root).unwrap();
+        assert!(sink.write_all(b"too early").is_err());
+
+        sink.start_file("site.bson").unwrap();
+        sink.write_all(b"site").unwrap();
+        sink.start_file("documents/atlas/main/index.bson").unwrap();
+        sink.write_all(b"index").unwrap();
+        assert!(sink.start_file("../escape.bson").is_err());
+        sink.finish().unwrap();
+
+        assert_eq!(std::fs::read(root.join("site.bson")).unwrap(), b"site");
+        assert_eq!(
+            std::fs::read(root.join("documents/atlas/main/index.bson")).unwrap(),
+            b"index"
+        );
+
+        // Writing over a previous output could leave parts of it behind
+        assert!(DirectorySink::create(&root).is_err());
+        assert!(DirectorySink::create(&root.join("site.bson")).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{bail, Result};
+
+use crate::analyzer::{Analyzer, AnalyzerContext};
+use crate::bundle;
+use crate::nodes;
+
+struct RegisteredPass<'a> {
+    name: &'static str,
+
+    /// Passes which must see each node before this one does
+    dependencies: Vec<&'static str>,
+    analyzer: Box<dyn Analyzer + 'a>,
+}
+
+/// Run several analyzers over a document in a single traversal. Upon entering each node, every
+/// pass sees it after the passes it depends on; upon exiting, in the reverse order. A
+/// dependency therefore only holds node by node, and a pass still sees the rest of the
+/// document before its dependencies have.
+pub struct PassManager<'a> {
+    passes: Vec<RegisteredPass<'a>>,
+    scheduled: bool,
+}
+
+impl<'a> Default for PassManager<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> PassManager<'a> {
+    pub fn new() -> Self {
+        Self {
+            passes: vec![],
+            scheduled: true,
+        }
+    }
+
+    /// Register a named pass which must see each node after each of the named dependencies.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        dependencies: &[&'static str],
+        analyzer: impl Analyzer + 'a,
+    ) -> &mut Self {
+        self.passes.push(RegisteredPass {
+            name,
+            dependencies: dependencies.to_owned(),
+            analyzer: Box::new(analyzer),
+        });
+        self.scheduled = false;
+        self
+    }
+
+    /// The names of the registered passes, in the order in which they run.
+    pub fn order(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name).collect()
+    }
+
+    /// Order passes so that each runs after its dependencies. Passes which do not depend on
+    /// each other keep their registration order.
+    pub fn schedule(&mut self) -> Result<()> {
+        for (i, pass) in self.passes.iter().enumerate() {
+            if self.passes[..i].iter().any(|other| other.name == pass.name) {
+                bail!("Pass {} is registered more than once", pass.name);
+            }
+
+            for dependency in &pass.dependencies {
+                if !self.passes.iter().any(|other| other.name == *dependency) {
+                    bail!(
+                        "Pass {} depends on unregistered pass {}",
+                        pass.name,
+                        dependency
+                    );
+                }
+            }
+        }
+
+        let mut pending = std::mem::take(&mut self.passes);
+        while !pending.is_empty() {
+            let ready = pending.iter().position(|pass| {
+                pass.dependencies.iter().all(|dependency| {
+                    self.passes
+                        .iter()
+                        .any(|scheduled| scheduled.name == *dependency)
+                })
+            });
+
+            match ready {
+                Some(i) => self.passes.push(pending.remove(i)),
+                None => {
+                    let names: Vec<&str> = pending.iter().map(|pass| pass.name).collect();
+                    bail!("Dependency cycle between passes: {}", names.join(", "));
+                }
+            }
+        }
+
+        self.scheduled = true;
+        Ok(())
+    }
+
+    /// Run every pass over a document in a single traversal.
+    pub fn run(&mut self, document: &mut nodes::Document) {
+        assert!(self.scheduled, "Passes must be scheduled before running");
+        document.run_analyzer(&mut Traversal {
+            passes: &mut self.passes,
+        });
+    }
+
+    /// Take the diagnostics raised by every pass since this was last called.
+    pub fn take_diagnostics(&mut self) -> Vec<bundle::Diagnostic> {
+        self.passes
+            .iter_mut()
+            .flat_map(|pass| pass.analyzer.take_diagnostics())
+            .collect()
+    }
+}
+
+/// Every registered pass, run together as a single analyzer.
+struct Traversal<'p, 'a> {
+    passes: &'p mut [RegisteredPass<'a>],
+}
+
+impl<'p, 'a> Analyzer for Traversal<'p, 'a> {
+    fn enter_page(&mut self, context: &AnalyzerContext, page: &nodes::Document) {
+        for pass in self.passes.iter_mut() {
+            pass.analyzer.enter_page(context, page);
+        }
+    }
+
+    fn exit_page(&mut self, context: &AnalyzerContext, page: &nodes::Document) {
+        for pass in self.passes.iter_mut().rev() {
+            pass.analyzer.exit_page(context, page);
+        }
+    }
+
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        for pass in self.passes.iter_mut() {
+            pass.analyzer.enter_node(context, node);
+        }
+    }
+
+    fn exit_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        for pass in self.passes.iter_mut().rev() {
+            pass.analyzer.exit_node(context, node);
+        }
+    }
+
+    fn needs_heading_titles(&self) -> bool {
+        self.passes
+            .iter()
+            .any(|pass| pass.analyzer.needs_heading_titles())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Log each root node entered and exited.
+    struct Recorder<'a> {
+        name: &'static str,
+        log: &'a Mutex<Vec<String>>,
+    }
+
+    impl<'a> Analyzer for Recorder<'a> {
+        fn enter_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) {
+            if let nodes::NodeData::Root(_) = node.data {
+                self.log
+                    .lock()
+                    .unwrap()
+                    .push(format!("enter {}", self.name));
+            }
+        }
+
+        fn exit_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) {
+            if let nodes::NodeData::Root(_) = node.data {
+                self.log.lock().unwrap().push(format!("exit {}", self.name));
+            }
+        }
+    }
+
+    #[test]
+    fn schedule() {
+        let log = Mutex::new(vec![]);
+        let mut passes = PassManager::new();
+        passes
+            .register(
+                "check",
+                &["rewrite", "targets"],
+                Recorder {
+                    name: "check",
+                    log: &log,
+                },
+            )
+            .register(
+                "targets",
+                &[],
+                Recorder {
+                    name: "targets",
+                    log: &log,
+                },
+            )
+            .register(
+                "rewrite",
+                &["targets"],
+                Recorder {
+                    name: "rewrite",
+                    log: &log,
+                },
+            )
+            .register(
+                "independent",
+                &[],
+                Recorder {
+                    name: "independent",
+                    log: &log,
+                },
+            );
+        passes.schedule().unwrap();
+        assert_eq!(
+            passes.order(),
+            vec!["targets", "rewrite", "check", "independent"]
+        );
+
+        let mut doc = nodes::tests::load_sample();
+        passes.run(&mut doc);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "enter targets",
+                "enter rewrite",
+                "enter check",
+                "enter independent",
+                "exit independent",
+                "exit check",
+                "exit rewrite",
+                "exit targets",
+            ]
+        );
+    }
+
+    #[test]
+    fn schedule_errors() {
+        let log = Mutex::new(vec![]);
+
+        let mut passes = PassManager::new();
+        passes.register(
+            "a",
+            &["missing"],
+            Recorder {
+                name: "a",
+                log: &log,
+            },
+        );
+        assert!(passes.schedule().is_err());
+
+        let mut passes = PassManager::new();
+        passes
+            .register(
+                "a",
+                &["b"],
+                Recorder {
+                    name: "a",
+                    log: &log,
+                },
+            )
+            .register(
+                "b",
+                &["a"],
+                Recorder {
+                    name: "b",
+                    log: &log,
+                },
+            );
+        assert!(passes.schedule().is_err());
+
+        let mut passes = PassManager::new();
+        for _ in 0..2 {
+            passes.register(
+                "a",
+                &[],
+                Recorder {
+                    name: "a",
+                    log: &log,
+                },
+            );
+        }
+        assert!(passes.schedule().is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::bundle;
+use crate::nodes;
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+/// An external program which transforms documents. Stitcher writes each document to the
+/// plugin's stdin as a BSON document, and reads the transformed document back from its
+/// stdout as a BSON document, one at a time and in order.
+///
+/// Plugins see each document once it has been migrated into its namespace, but before
+/// substitutions are expanded and links are rewritten and checked, so anything a plugin adds
+/// is treated like the rest of the page. Each document passes through the plugins twice: once
+/// while linking, so that targets they add can be linked to, and again while splicing. A
+/// plugin must give the same result both times.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    pub name: String,
+
+    /// The program to run, followed by its arguments
+    pub command: Vec<String>,
+
+    /// How long to wait for the plugin to return each document
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+/// Read one length-prefixed BSON document from a stream. Returns None at end of stream.
+fn read_bson_bytes(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut length_bytes = [0_u8; 4];
+    match reader.read_exact(&mut length_bytes) {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let length = i32::from_le_bytes(length_bytes);
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|length| *length >= 5)
+        .ok_or_else(|| anyhow!("Invalid BSON document length {}", length))?;
+
+    let mut buf = Vec::with_capacity(length);
+    buf.extend_from_slice(&length_bytes);
+    buf.resize(length, 0);
+    reader.read_exact(&mut buf[4..])?;
+    Ok(Some(buf))
+}
+
+struct RunningPlugin {
+    child: Child,
+    requests: crossbeam_channel::Sender<Vec<u8>>,
+    responses: crossbeam_channel::Receiver<Result<Vec<u8>>>,
+}
+
+impl RunningPlugin {
+    fn spawn(config: &PluginConfig) -> Result<Self> {
+        let (program, args) = config
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow!("Plugin {} has an empty command", config.name))?;
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start plugin {}", config.name))?;
+
+        // Feed and drain the plugin on separate threads, so that a plugin which stops
+        // reading or writing can never block us past the timeout.
+        let mut stdin = BufWriter::new(child.stdin.take().unwrap());
+        let (request_tx, request_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        std::thread::spawn(move || {
+            for request in request_rx {
+                if stdin
+                    .write_all(&request)
+                    .and_then(|_| stdin.flush())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let (response_tx, response_rx) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || loop {
+            let response = match read_bson_bytes(&mut stdout) {
+                Ok(Some(response)) => Ok(response),
+                Ok(None) => return,
+                Err(err) => Err(err),
+            };
+
+            let failed = response.is_err();
+            if response_tx.send(response).is_err() || failed {
+                return;
+            }
+        });
+
+        Ok(Self {
+            child,
+            requests: request_tx,
+            responses: response_rx,
+        })
+    }
+}
+
+impl Drop for RunningPlugin {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A plugin process. The process is started on first use, and restarted after a failure so
+/// that one bad document does not affect the next.
+pub struct Plugin {
+    config: PluginConfig,
+    process: Option<RunningPlugin>,
+}
+
+impl Plugin {
+    pub fn new(config: &PluginConfig) -> Self {
+        Self {
+            config: config.to_owned(),
+            process: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Send a document through the plugin, returning the transformed document.
+    pub fn transform(&mut self, document: &nodes::Document) -> Result<nodes::Document> {
+        let result = self.transform_inner(document);
+        if result.is_err() {
+            self.process = None;
+        }
+
+        result
+    }
+
+    fn transform_inner(&mut self, document: &nodes::Document) -> Result<nodes::Document> {
+        if self.process.is_none() {
+            self.process = Some(RunningPlugin::spawn(&self.config)?);
+        }
+        let process = self.process.as_mut().unwrap();
+
+        process
+            .requests
+            .send(bson::to_vec(document)?)
+            .map_err(|_| anyhow!("Plugin {} stopped reading input", self.config.name))?;
+
+        let response = match process
+            .responses
+            .recv_timeout(Duration::from_secs(self.config.timeout_secs))
+        {
+            Ok(response) => response?,
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => bail!(
+                "Plugin {} timed out after {} seconds",
+                self.config.name,
+                self.config.timeout_secs
+            ),
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                let status = process.child.wait()?;
+                bail!("Plugin {} exited early: {}", self.config.name, status)
+            }
+        };
+
+        bson::from_slice(&response)
+            .with_context(|| format!("Plugin {} returned an invalid document", self.config.name))
+    }
+}
+
+/// Run a document through each plugin in turn. A plugin which fails leaves the document as it
+/// was, and is reported as a diagnostic.
+pub fn run_plugins(
+    plugins: &mut [Plugin],
+    document: &mut nodes::Document,
+) -> Vec<bundle::Diagnostic> {
+    let mut diagnostics = vec![];
+    for plugin in plugins {
+        match plugin.transform(document) {
+            Ok(transformed) => *document = transformed,
+            Err(err) => {
+                log::error!("{}: {:#}", document.page_id, err);
+                diagnostics.push(bundle::Diagnostic::new(
+                    bundle::Severity::Error,
+                    0,
+                    format!("Plugin {} failed: {:#}", plugin.name(), err),
+                ));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    /// The path to the reference plugin in examples/, building it if `cargo test` has not
+    /// already done so.
+    pub(crate) fn banner_plugin() -> PathBuf {
+        let target_dir = std::env::current_exe()
+            .unwrap()
+            .parent()
+            .and_then(Path::parent)
+            .unwrap()
+            .to_owned();
+        let path = target_dir
+            .join("examples")
+            .join(format!("banner_plugin{}", std::env::consts::EXE_SUFFIX));
+
+        if !path.exists() {
+            let status = Command::new(env!("CARGO"))
+                .args(["build", "--example", "banner_plugin"])
+                .status()
+                .unwrap();
+            assert!(status.success(), "Failed to build the banner plugin");
+        }
+
+        path
+    }
+
+    fn make_plugin(command: &[&str], timeout_secs: u64) -> Plugin {
+        Plugin::new(&PluginConfig {
+            name: "test".to_owned(),
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            timeout_secs,
+        })
+    }
+
+    #[test]
+    fn identity() {
+        // cat is the simplest possible plugin: it returns each document unchanged
+        let mut plugins = vec![make_plugin(&["cat"], 10)];
+        let mut doc = nodes::tests::load_sample();
+        let original_text = doc.ast.get_text();
+
+        for _ in 0..3 {
+            assert!(run_plugins(&mut plugins, &mut doc).is_empty());
+            assert_eq!(doc.page_id, "bi-connector/heli/master/supported-operations");
+            assert_eq!(doc.ast.get_text(), original_text);
+        }
+    }
+
+    #[test]
+    fn banner_example() {
+        let path = banner_plugin();
+        let mut plugins = vec![make_plugin(&[path.to_str().unwrap(), "Preview"], 10)];
+        let mut doc = nodes::tests::load_sample();
+        let original_text = doc.ast.get_text();
+
+        // The plugin is kept running between documents, and handles each in turn
+        for i in 1..=2 {
+            assert!(run_plugins(&mut plugins, &mut doc).is_empty());
+            let banners: Vec<String> = doc
+                .ast
+                .data
+                .children()
+                .iter()
+                .take_while(|node| match &node.data {
+                    nodes::NodeData::Directive(directive) => directive.name == "banner",
+                    _ => false,
+                })
+                .map(|node| node.get_text())
+                .collect();
+            assert_eq!(banners, vec!["Preview"; i]);
+            assert_eq!(doc.ast.get_text(), "Preview".repeat(i) + &original_text);
+        }
+    }
+
+    #[test]
+    fn failures() {
+        let mut doc = nodes::tests::load_sample();
+        let original_text = doc.ast.get_text();
+
+        let mut plugins = vec![
+            make_plugin(&["sh", "-c", "exit 1"], 10),
+            make_plugin(&["sh", "-c", "sleep 10"], 1),
+            make_plugin(&["sh", "-c", "printf '\\377\\377\\377\\377'"], 10),
+            make_plugin(&["stitcher-plugin-that-does-not-exist"], 10),
+        ];
+        let diagnostics = run_plugins(&mut plugins, &mut doc);
+        assert_eq!(doc.ast.get_text(), original_text);
+
+        let messages: Vec<&str> = diagnostics.iter().map(|diag| diag.message()).collect();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].contains("exited early"), "{}", messages[0]);
+        assert!(messages[1].contains("timed out"), "{}", messages[1]);
+        assert!(messages[2].contains("Invalid BSON"), "{}", messages[2]);
+        assert!(messages[3].contains("Failed to start"), "{}", messages[3]);
+    }
+}
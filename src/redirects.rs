@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::bundle;
+use crate::nodes;
+use crate::sitemap;
+
+/// Pages whose titles are at least this similar may be matched even if their fileids differ.
+const MIN_TITLE_SIMILARITY: f64 = 0.9;
+
+/// Pages built from the same source file must have titles at least this similar, so that a
+/// page rewritten from scratch is not mistaken for the one it replaced.
+const MIN_SAME_FILE_TITLE_SIMILARITY: f64 = 0.5;
+
+/// What we need to know about a stitched page to find where it moved.
+#[derive(Debug, Clone)]
+pub struct PageInfo {
+    pub page_id: String,
+
+    /// The page's source file, relative to its project
+    pub fileid: String,
+    pub title: String,
+}
+
+impl PageInfo {
+    pub fn from_document(document: &nodes::Document) -> Self {
+        Self {
+            page_id: document.page_id.to_owned(),
+            fileid: document.filename.as_posix(),
+            title: document.title().unwrap_or_default(),
+        }
+    }
+
+    /// The project a page belongs to: the first component of its namespaced page id.
+    fn project(&self) -> &str {
+        self.page_id.split('/').next().unwrap_or_default()
+    }
+}
+
+/// Read the pages of a previous stitched output.
+pub fn load_pages(path: impl AsRef<Path>) -> Result<Vec<PageInfo>> {
+    let mut bundle = bundle::Bundle::open(path)?;
+    let mut pages = vec![];
+    for entry in &mut bundle {
+        if let bundle::BundleElementData::Document(document) = entry?.data {
+            pages.push(PageInfo::from_document(&document));
+        }
+    }
+
+    Ok(pages)
+}
+
+fn title_words(title: &str) -> BTreeSet<String> {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// The Jaccard similarity of the words in two titles, from 0 to 1.
+fn title_similarity(a: &BTreeSet<String>, b: &BTreeSet<String>) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    let common = a.intersection(b).count();
+    common as f64 / (a.len() + b.len() - common) as f64
+}
+
+/// The number of trailing path components two page ids share.
+fn common_suffix(a: &str, b: &str) -> usize {
+    a.rsplit('/')
+        .zip(b.rsplit('/'))
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Map each page of the previous output which no longer exists to the page which most likely
+/// replaced it, within the same project, both given as the paths the pages are served at, as in
+/// the sitemap. A page is preferably matched to a new page built from the same source file with
+/// a similar title, choosing the most similar title, then the most similar path; otherwise, to
+/// a new page with a near-identical title. Pages with no plausible match are left out.
+pub fn find_redirects(previous: &[PageInfo], current: &[PageInfo]) -> BTreeMap<String, String> {
+    let previous_ids: HashSet<&str> = previous.iter().map(|page| page.page_id.as_str()).collect();
+    let current_ids: HashSet<&str> = current.iter().map(|page| page.page_id.as_str()).collect();
+    let added: Vec<(&PageInfo, BTreeSet<String>)> = current
+        .iter()
+        .filter(|page| !previous_ids.contains(page.page_id.as_str()))
+        .map(|page| (page, title_words(&page.title)))
+        .collect();
+
+    let mut added_by_project: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut added_by_file: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for (i, (page, _)) in added.iter().enumerate() {
+        added_by_project.entry(page.project()).or_default().push(i);
+        added_by_file
+            .entry((page.project(), &page.fileid))
+            .or_default()
+            .push(i);
+    }
+
+    let mut redirects = BTreeMap::new();
+    for old in previous
+        .iter()
+        .filter(|page| !current_ids.contains(page.page_id.as_str()))
+    {
+        let old_words = title_words(&old.title);
+        let score = |i: usize| {
+            let (new, new_words) = &added[i];
+            (
+                title_similarity(&old_words, new_words),
+                common_suffix(&old.page_id, &new.page_id),
+                // Break any remaining tie deterministically
+                std::cmp::Reverse(new.page_id.as_str()),
+            )
+        };
+        let best = |candidates: Option<&Vec<usize>>, min_similarity: f64| {
+            candidates
+                .into_iter()
+                .flatten()
+                .filter(|i| title_similarity(&old_words, &added[**i].1) >= min_similarity)
+                .map(|i| (score(*i), *i))
+                .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+                .map(|(_, i)| added[i].0)
+        };
+
+        let new = best(
+            added_by_file.get(&(old.project(), old.fileid.as_str())),
+            MIN_SAME_FILE_TITLE_SIMILARITY,
+        )
+        .or_else(|| best(added_by_project.get(old.project()), MIN_TITLE_SIMILARITY));
+
+        if let Some(new) = new {
+            redirects.insert(
+                sitemap::page_path(&old.page_id),
+                sitemap::page_path(&new.page_id),
+            );
+        }
+    }
+
+    redirects
+}
+
+pub fn write_json(redirects: &BTreeMap<String, String>, writer: impl Write) -> Result<()> {
+    serde_json::to_writer_pretty(writer, redirects)?;
+    Ok(())
+}
+
+/// Quote a string for an nginx config file.
+fn nginx_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Percent-encode every character of a path other than unreserved characters and slashes, so
+/// that nothing in it can be taken as an nginx variable or end the directive.
+fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded += &format!("%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+/// Write nginx rewrite rules for a set of redirects, matching each old path with or without
+/// a trailing slash.
+pub fn write_nginx(redirects: &BTreeMap<String, String>, mut writer: impl Write) -> Result<()> {
+    for (from, to) in redirects {
+        writeln!(
+            writer,
+            "rewrite {} {} permanent;",
+            nginx_quote(&format!(
+                "^{}/?$",
+                regex::escape(from.trim_end_matches('/'))
+            )),
+            nginx_quote(&percent_encode_path(to))
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn page(page_id: &str, fileid: &str, title: &str) -> PageInfo {
+        PageInfo {
+            page_id: page_id.to_owned(),
+            fileid: fileid.to_owned(),
+            title: title.to_owned(),
+        }
+    }
+
+    #[test]
+    fn redirects() {
+        let previous = vec![
+            page("atlas/v1/index", "index.txt", "MongoDB Atlas"),
+            page(
+                "atlas/v1/tutorial/connect",
+                "tutorial/connect.txt",
+                "Connect",
+            ),
+            page(
+                "atlas/v1/old-name",
+                "old-name.txt",
+                "Configure Network Peering",
+            ),
+            page("atlas/v1/removed", "removed.txt", "Deprecated Feature"),
+            // Sharing a fileid is not enough to match if the page was rewritten
+            page("atlas/v1/faq", "faq.txt", "Frequently Asked Questions"),
+            page("compass/main/index", "index.txt", "MongoDB Compass"),
+            // Sharing a fileid or a title with a page of another project is not enough
+            page("realm/main/index", "index.txt", "MongoDB Realm"),
+            page("realm/main/charts", "charts.txt", "MongoDB Charts"),
+        ];
+        let current = vec![
+            page("atlas/v2/index", "index.txt", "MongoDB Atlas"),
+            page(
+                "atlas/v2/tutorial/connect",
+                "tutorial/connect.txt",
+                "Connect",
+            ),
+            page(
+                "atlas/v2/new-name",
+                "new-name.txt",
+                "Configure network peering",
+            ),
+            page(
+                "atlas/v2/troubleshooting",
+                "faq.txt",
+                "Troubleshooting Connections",
+            ),
+            page("charts/main/index", "index.txt", "MongoDB Charts"),
+            page("compass/main/index", "index.txt", "MongoDB Compass"),
+        ];
+
+        let redirects = find_redirects(&previous, &current);
+        let expected: BTreeMap<String, String> = [
+            ("/atlas/v1/", "/atlas/v2/"),
+            ("/atlas/v1/old-name/", "/atlas/v2/new-name/"),
+            ("/atlas/v1/tutorial/connect/", "/atlas/v2/tutorial/connect/"),
+        ]
+        .iter()
+        .map(|(from, to)| (from.to_string(), to.to_string()))
+        .collect();
+        assert_eq!(redirects, expected);
+
+        let mut out = vec![];
+        write_nginx(&redirects, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().lines().next().unwrap(),
+            r#"rewrite "^/atlas/v1/?$" "/atlas/v2/" permanent;"#
+        );
+    }
+
+    #[test]
+    fn nginx_escaping() {
+        let redirects: BTreeMap<String, String> = [(
+            r#"/old page/"quoted"$path/"#.to_owned(),
+            "/new page;$host/".to_owned(),
+        )]
+        .into_iter()
+        .collect();
+
+        let mut out = vec![];
+        write_nginx(&redirects, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().trim_end(),
+            r#"rewrite "^/old page/\"quoted\"\\$path/?$" "/new%20page%3B%24host/" permanent;"#
+        );
+    }
+}
@@ -0,0 +1,540 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::bundle;
+use crate::links;
+use crate::markdown;
+use crate::nodes;
+use crate::output;
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The format to render pages in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Html,
+    Markdown,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Markdown => "md",
+        }
+    }
+}
+
+/// The path of the page a document is rendered to, relative to the output directory and
+/// without an extension: e.g. "atlas/main/tutorial/connect".
+pub(crate) fn page_path(document: &nodes::Document) -> String {
+    match &document.ast.data {
+        nodes::NodeData::Root(root) => root.fileid.without_known_suffix(),
+        _ => document.page_id.to_owned(),
+    }
+}
+
+/// Link from one rendered page to another, given as a site-absolute path such as
+/// "/atlas/main/faq" or "/atlas/main/", with an optional fragment.
+pub(crate) fn relative_href(
+    from_page: &str,
+    to_path: &str,
+    fragment: Option<&str>,
+    format: Format,
+) -> String {
+    let mut to_path = to_path.trim_start_matches('/').to_owned();
+    if to_path.is_empty() || to_path.ends_with('/') {
+        to_path += "index";
+    }
+
+    let depth = from_page.matches('/').count();
+    let mut href = "../".repeat(depth);
+    href += &to_path;
+    href += ".";
+    href += format.extension();
+    if let Some(fragment) = fragment {
+        href += "#";
+        href += fragment;
+    }
+    href
+}
+
+/// Resolve a reference's target from a rendered page. Internal links become relative links to
+/// the rendered page, without any query string; external links are left unchanged.
+pub(crate) fn resolve_refuri(from_page: &str, refuri: &str, format: Format) -> String {
+    if !links::is_internal_refuri(refuri) {
+        return refuri.to_owned();
+    }
+
+    let (path, fragment) = links::split_fragment(refuri);
+    relative_href(from_page, path, fragment, format)
+}
+
+/// Renders a document's AST into simple semantic HTML.
+struct Renderer<'a> {
+    page: &'a str,
+    section_depth: usize,
+    out: String,
+}
+
+impl<'a> Renderer<'a> {
+    fn children(&mut self, children: &[nodes::Node]) {
+        for child in children {
+            self.node(child);
+        }
+    }
+
+    fn wrap(&mut self, tag: &str, attributes: &str, children: &[nodes::Node]) {
+        let _ = write!(self.out, "<{tag}{attributes}>");
+        self.children(children);
+        let _ = write!(self.out, "</{tag}>");
+    }
+
+    fn code(&mut self, code: &nodes::Code) {
+        let emphasized = |line: i32| {
+            code.emphasize_lines
+                .iter()
+                .flatten()
+                .any(|(start, end)| (*start..=*end).contains(&line))
+        };
+
+        self.out.push_str("<figure class=\"code\">");
+        if let Some(caption) = &code.caption {
+            let _ = write!(
+                self.out,
+                "<figcaption>{}</figcaption>",
+                escape_html(caption)
+            );
+        }
+
+        match &code.lang {
+            Some(lang) => {
+                let _ = write!(
+                    self.out,
+                    "<pre><code class=\"language-{}\">",
+                    escape_html(lang)
+                );
+            }
+            None => self.out.push_str("<pre><code>"),
+        }
+
+        let first_line = code.lineno_start.unwrap_or(1);
+        for (i, line) in code.value.lines().enumerate() {
+            let number = first_line + i as i32;
+            if i > 0 {
+                self.out.push('\n');
+            }
+
+            if emphasized(i as i32 + 1) {
+                self.out.push_str("<mark>");
+            }
+            if code.linenos {
+                let _ = write!(self.out, "<span class=\"lineno\">{number}</span>");
+            }
+            self.out.push_str(&escape_html(line));
+            if emphasized(i as i32 + 1) {
+                self.out.push_str("</mark>");
+            }
+        }
+        self.out.push_str("</code></pre></figure>");
+    }
+
+    fn directive(&mut self, node: &nodes::Node, directive: &nodes::Directive) {
+        if let Some(entries) = &directive.entries {
+            self.out.push_str("<nav class=\"toctree\"><ul>");
+            for entry in entries {
+                let href = match (&entry.slug, &entry.url) {
+                    (Some(slug), _) => relative_href(self.page, slug, None, Format::Html),
+                    (None, Some(url)) => url.to_owned(),
+                    (None, None) => continue,
+                };
+                let title = entry.title.as_deref().unwrap_or(href.as_str());
+                let _ = write!(
+                    self.out,
+                    "<li><a href=\"{}\">{}</a></li>",
+                    escape_html(&href),
+                    escape_html(title)
+                );
+            }
+            self.out.push_str("</ul></nav>");
+            return;
+        }
+
+        let _ = write!(
+            self.out,
+            "<div class=\"directive {}\">",
+            escape_html(&directive.name)
+        );
+        if !directive.argument.is_empty() {
+            self.wrap("p", " class=\"directive-argument\"", &directive.argument);
+        }
+        self.children(node.data.children());
+        self.out.push_str("</div>");
+    }
+
+    fn node(&mut self, node: &nodes::Node) {
+        let children = node.data.children();
+        match &node.data {
+            nodes::NodeData::Comment(_)
+            | nodes::NodeData::SubstitutionDefinition(_)
+            | nodes::NodeData::TargetIdentifier(_)
+            | nodes::NodeData::NamedReference(_) => (),
+            nodes::NodeData::Root(_)
+            | nodes::NodeData::SubstitutionReference(_)
+            | nodes::NodeData::BlockSubstitutionReference(_)
+            | nodes::NodeData::DirectiveArgument(_) => self.children(children),
+            nodes::NodeData::Text(text) => self.out.push_str(&escape_html(&text.value)),
+            nodes::NodeData::Code(code) => self.code(code),
+            nodes::NodeData::Label(_) => self.wrap("span", " class=\"label\"", children),
+            nodes::NodeData::Section(_) => {
+                self.section_depth += 1;
+                self.wrap("section", "", children);
+                self.section_depth -= 1;
+            }
+            nodes::NodeData::Heading(heading) => {
+                let level = self.section_depth.clamp(1, 6);
+                let attributes = format!(" id=\"{}\"", escape_html(&heading.id));
+                self.wrap(&format!("h{level}"), &attributes, children);
+            }
+            nodes::NodeData::Paragraph(_) => self.wrap("p", "", children),
+            nodes::NodeData::Footnote(footnote) => {
+                let attributes =
+                    format!(" class=\"footnote\" id=\"{}\"", escape_html(&footnote.id));
+                self.wrap("aside", &attributes, children);
+            }
+            nodes::NodeData::FootnoteReference(reference) => {
+                let _ = write!(
+                    self.out,
+                    "<sup><a class=\"footnote-reference\" href=\"#{}\">",
+                    escape_html(&reference.id)
+                );
+                if children.is_empty() {
+                    let _ = write!(self.out, "[{}]", escape_html(&reference.id));
+                }
+                self.children(children);
+                self.out.push_str("</a></sup>");
+            }
+            nodes::NodeData::DefinitionList(_) => self.wrap("dl", "", children),
+            nodes::NodeData::DefinitionListItem(item) => {
+                self.wrap("dt", "", &item.term);
+                self.wrap("dd", "", children);
+            }
+            nodes::NodeData::List(list) => {
+                let list_type = match list.enumtype {
+                    nodes::ListEnumType::Unordered => None,
+                    nodes::ListEnumType::Arabic => Some("1"),
+                    nodes::ListEnumType::LowerAlpha => Some("a"),
+                    nodes::ListEnumType::UpperAlpha => Some("A"),
+                    nodes::ListEnumType::LowerRoman => Some("i"),
+                    nodes::ListEnumType::UpperRoman => Some("I"),
+                };
+
+                match list_type {
+                    None => self.wrap("ul", "", children),
+                    Some(list_type) => {
+                        let mut attributes = format!(" type=\"{list_type}\"");
+                        if let Some(start) = list.startat {
+                            let _ = write!(attributes, " start=\"{start}\"");
+                        }
+                        self.wrap("ol", &attributes, children);
+                    }
+                }
+            }
+            nodes::NodeData::ListItem(_) => self.wrap("li", "", children),
+            nodes::NodeData::LineBlock(_) => self.wrap("div", " class=\"line-block\"", children),
+            nodes::NodeData::Line(_) => self.wrap("div", " class=\"line\"", children),
+            nodes::NodeData::Directive(directive) => self.directive(node, directive),
+            nodes::NodeData::Target(target) => {
+                if let Some(html_id) = &target.html_id {
+                    let _ = write!(self.out, "<span id=\"{}\"></span>", escape_html(html_id));
+                }
+                self.children(children);
+            }
+            nodes::NodeData::InlineTarget(inline) => {
+                if let Some(html_id) = &inline.target.html_id {
+                    let _ = write!(self.out, "<span id=\"{}\"></span>", escape_html(html_id));
+                }
+            }
+            nodes::NodeData::Reference(reference) => {
+                let href = resolve_refuri(self.page, &reference.refuri, Format::Html);
+                let _ = write!(self.out, "<a href=\"{}\">", escape_html(&href));
+                if children.is_empty() {
+                    self.out.push_str(&escape_html(&reference.refuri));
+                }
+                self.children(children);
+                self.out.push_str("</a>");
+            }
+            nodes::NodeData::Role(role) => {
+                let attributes = format!(" class=\"role {}\"", escape_html(&role.name));
+                if children.is_empty() {
+                    let _ = write!(
+                        self.out,
+                        "<span{}>{}</span>",
+                        attributes,
+                        escape_html(&role.target)
+                    );
+                } else {
+                    self.wrap("span", &attributes, children);
+                }
+            }
+            nodes::NodeData::RefRole(refrole) => {
+                let href = match (&refrole.fileid, &refrole.url) {
+                    (Some((page, html_id)), _) => {
+                        let fragment = Some(html_id.as_str()).filter(|id| !id.is_empty());
+                        Some(relative_href(self.page, page, fragment, Format::Html))
+                    }
+                    (None, Some(url)) => Some(url.to_owned()),
+                    (None, None) => None,
+                };
+
+                match &href {
+                    Some(href) => {
+                        let _ = write!(self.out, "<a href=\"{}\">", escape_html(href));
+                    }
+                    None => self.out.push_str("<span class=\"unresolved\">"),
+                }
+                if children.is_empty() {
+                    self.out.push_str(&escape_html(&refrole.role.target));
+                }
+                self.children(children);
+                self.out
+                    .push_str(if href.is_some() { "</a>" } else { "</span>" });
+            }
+            nodes::NodeData::Literal(_) => self.wrap("code", "", children),
+            nodes::NodeData::Emphasis(_) => self.wrap("em", "", children),
+            nodes::NodeData::Strong(_) => self.wrap("strong", "", children),
+            nodes::NodeData::FieldList(_) => self.wrap("dl", " class=\"field-list\"", children),
+            nodes::NodeData::Field(field) => {
+                let name = field.label.as_deref().unwrap_or(&field.name);
+                let _ = write!(self.out, "<dt>{}</dt>", escape_html(name));
+                self.wrap("dd", "", children);
+            }
+            nodes::NodeData::Transition(_) => self.out.push_str("<hr>"),
+        }
+    }
+}
+
+/// Render a stitched document as a standalone HTML page.
+pub fn render_document(document: &nodes::Document) -> String {
+    let page = page_path(document);
+    let mut renderer = Renderer {
+        page: &page,
+        section_depth: 0,
+        out: String::new(),
+    };
+    renderer.node(&document.ast);
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n\
+         <body>\n<main>\n{}\n</main>\n</body>\n</html>\n",
+        escape_html(
+            &document
+                .title()
+                .unwrap_or_else(|| document.page_id.to_owned())
+        ),
+        renderer.out
+    )
+}
+
+/// Where a rendered document is written within the output directory. Page paths come from
+/// the bundle, so any which would lead outside the output directory are rejected.
+fn document_path(output: &Path, document: &nodes::Document, format: Format) -> Result<PathBuf> {
+    output::join_within(
+        output,
+        format!("{}.{}", page_path(document), format.extension()),
+    )
+}
+
+/// Render every document of a stitched bundle into a directory of static pages, one per
+/// page path, and copy out its assets. Returns the number of pages written.
+pub fn render_bundle(bundle_path: &Path, output: &Path, format: Format) -> Result<usize> {
+    let mut bundle = bundle::Bundle::open(bundle_path)?;
+    let mut n_pages = 0;
+
+    for entry in &mut bundle {
+        let entry = entry?;
+        let (path, contents) = match &entry.data {
+            bundle::BundleElementData::Document(document) => (
+                document_path(output, document, format)?,
+                match format {
+                    Format::Html => render_document(document),
+                    Format::Markdown => markdown::render_document(document),
+                }
+                .into_bytes(),
+            ),
+            bundle::BundleElementData::Asset(asset) => (
+                output::join_within(&output.join("assets"), &entry.name)?,
+                asset.to_owned(),
+            ),
+            bundle::BundleElementData::Diagnostics(_) => continue,
+        };
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Error writing {}", path.display()))?;
+
+        if let bundle::BundleElementData::Document(_) = entry.data {
+            n_pages += 1;
+        }
+    }
+
+    Ok(n_pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn hrefs() {
+        assert_eq!(
+            relative_href(
+                "atlas/main/tutorial/connect",
+                "/atlas/main/faq",
+                Some("top"),
+                Format::Html
+            ),
+            "../../../atlas/main/faq.html#top"
+        );
+        assert_eq!(
+            relative_href("index", "/atlas/main/", None, Format::Markdown),
+            "atlas/main/index.md"
+        );
+
+        // Query strings are dropped, since the rendered pages are static files
+        assert_eq!(
+            resolve_refuri("atlas/main/faq", "/atlas/main/page?x=1#a", Format::Html),
+            "../../atlas/main/page.html#a"
+        );
+        assert_eq!(
+            resolve_refuri("atlas/main/faq", "/atlas/main/page?x=1", Format::Markdown),
+            "../../atlas/main/page.md"
+        );
+        assert_eq!(
+            resolve_refuri("index", "https://example.com/page?x=1#a", Format::Html),
+            "https://example.com/page?x=1#a"
+        );
+    }
+
+    #[test]
+    fn untrusted_paths() {
+        let make_doc = |fileid: &str| -> nodes::Document {
+            bson::from_bson(bson::bson!({
+                "page_id": "atlas/main/index",
+                "filename": "index.txt",
+                "source": "",
+                "static_assets": [],
+                "ast": {
+                    "type": "root",
+                    "position": {"start": {"line": 0}},
+                    "fileid": fileid,
+                    "children": []
+                }
+            }))
+            .unwrap()
+        };
+
+        let output = Path::new("preview");
+        assert_eq!(
+            document_path(output, &make_doc("atlas/main/index.txt"), Format::Html).unwrap(),
+            Path::new("preview/atlas/main/index.html")
+        );
+        for fileid in [
+            "../../etc/evil.txt",
+            "atlas/../../evil.txt",
+            "/tmp/evil.txt",
+        ] {
+            assert!(
+                document_path(output, &make_doc(fileid), Format::Html).is_err(),
+                "{}",
+                fileid
+            );
+        }
+    }
+
+    #[test]
+    fn render() {
+        let doc: nodes::Document = bson::from_bson(bson::bson!({
+            "page_id": "atlas/main/index",
+            "filename": "index.txt",
+            "source": "",
+            "static_assets": [],
+            "ast": {
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "fileid": "atlas/main/index.txt",
+                "children": [{
+                    "type": "section",
+                    "position": {"start": {"line": 0}},
+                    "children": [
+                        {
+                            "type": "heading",
+                            "position": {"start": {"line": 0}},
+                            "id": "overview",
+                            "children": [{"type": "text", "position": {"start": {"line": 0}}, "value": "Overview & Intro"}]
+                        },
+                        {
+                            "type": "list",
+                            "position": {"start": {"line": 1}},
+                            "enumtype": "loweralpha",
+                            "startat": 3,
+                            "children": [{
+                                "type": "listItem",
+                                "position": {"start": {"line": 1}},
+                                "children": [{
+                                    "type": "ref_role",
+                                    "position": {"start": {"line": 1}},
+                                    "domain": "std",
+                                    "name": "label",
+                                    "target": "connect",
+                                    "flag": "",
+                                    "fileid": ["atlas/main/tutorial/connect", "std-label-connect"],
+                                    "children": []
+                                }]
+                            }]
+                        },
+                        {
+                            "type": "code",
+                            "position": {"start": {"line": 2}},
+                            "lang": "python",
+                            "copyable": true,
+                            "emphasize_lines": [[2, 2]],
+                            "value": "a = 1\nb = a < 2",
+                            "linenos": false
+                        }
+                    ]
+                }]
+            }
+        }))
+        .unwrap();
+
+        let html = render_document(&doc);
+        assert!(html.contains("<title>Overview &amp; Intro</title>"));
+        assert!(html.contains("<h1 id=\"overview\">Overview &amp; Intro</h1>"));
+        assert!(html.contains(
+            "<ol type=\"a\" start=\"3\"><li><a href=\"../../atlas/main/tutorial/connect.html#std-label-connect\">connect</a></li></ol>"
+        ));
+        assert!(html.contains(
+            "<pre><code class=\"language-python\">a = 1\n<mark>b = a &lt; 2</mark></code></pre>"
+        ));
+    }
+}
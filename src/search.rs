@@ -0,0 +1,116 @@
+use std::io::Write;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::analyzer::{Visitor, VisitorContext};
+use crate::nodes;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SearchHeading {
+    pub id: String,
+    pub title: String,
+}
+
+/// The searchable contents of one page. The index is written as JSON lines, one record per
+/// page, sorted by page_id.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchRecord {
+    pub page_id: String,
+    pub namespace: String,
+    pub title: String,
+    pub headings: Vec<SearchHeading>,
+    pub text: String,
+    pub code: Vec<String>,
+    pub facets: Vec<nodes::Facet>,
+}
+
+/// Collects the headings, paragraph text and code of a page.
+#[derive(Default)]
+struct SearchVisitor {
+    headings: Vec<SearchHeading>,
+    paragraphs: Vec<String>,
+    code: Vec<String>,
+}
+
+impl<'a> Visitor<'a> for SearchVisitor {
+    fn enter_node(&mut self, _context: &VisitorContext<'a>, node: &'a nodes::Node) {
+        match &node.data {
+            nodes::NodeData::Heading(heading) => self.headings.push(SearchHeading {
+                id: heading.id.to_owned(),
+                title: node.get_text(),
+            }),
+            nodes::NodeData::Paragraph(_) => {
+                let text = node.get_text();
+                if !text.trim().is_empty() {
+                    self.paragraphs.push(text);
+                }
+            }
+            nodes::NodeData::Code(code) => self.code.push(code.value.to_owned()),
+            _ => (),
+        }
+    }
+}
+
+/// Extract the search record of a stitched document. The page's title is its first heading.
+pub fn index_document(document: &nodes::Document, namespace: &str) -> SearchRecord {
+    let mut visitor = SearchVisitor::default();
+    document.ast.visit(&mut visitor);
+
+    SearchRecord {
+        page_id: document.page_id.to_owned(),
+        namespace: namespace.to_owned(),
+        title: visitor
+            .headings
+            .first()
+            .map(|heading| heading.title.to_owned())
+            .unwrap_or_default(),
+        headings: visitor.headings,
+        text: visitor.paragraphs.join("\n"),
+        code: visitor.code,
+        facets: document.facets.to_owned().unwrap_or_default(),
+    }
+}
+
+pub fn write_index(records: &mut [SearchRecord], mut writer: impl Write) -> Result<()> {
+    records.sort_by(|a, b| a.page_id.cmp(&b.page_id));
+    for record in records.iter() {
+        serde_json::to_writer(&mut writer, record)?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn index() {
+        let doc = nodes::tests::load_sample();
+        let record = index_document(&doc, "bi-connector/master");
+
+        assert_eq!(
+            record.page_id,
+            "bi-connector/heli/master/supported-operations"
+        );
+        assert_eq!(record.namespace, "bi-connector/master");
+        assert_eq!(record.title, record.headings[0].title);
+        assert!(!record.title.is_empty());
+        assert!(record.headings.iter().all(|heading| !heading.id.is_empty()));
+        assert!(!record.text.is_empty());
+
+        let mut out = vec![];
+        write_index(&mut [record.clone(), record], &mut out).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["title"], lines[0]["headings"][0]["title"]);
+    }
+}
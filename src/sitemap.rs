@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use crate::analyzer::{Visitor, VisitorContext};
+use crate::nodes;
+
+/// The most URLs a single sitemap file may list, per the sitemaps.org protocol.
+const MAX_URLS_PER_SITEMAP: usize = 50_000;
+
+/// Return true if a set of page or meta options asks search engines not to index the page,
+/// either with a "noindex" flag or a "robots" value such as "noindex, nofollow".
+fn options_noindex(options: &HashMap<String, bson::Bson>) -> bool {
+    let noindex = match options.get("noindex") {
+        Some(bson::Bson::Boolean(value)) => *value,
+        Some(bson::Bson::String(value)) => value != "false",
+        Some(bson::Bson::Null) => true,
+        _ => false,
+    };
+
+    let robots = match options.get("robots") {
+        Some(bson::Bson::String(value)) => value
+            .split(',')
+            .any(|directive| directive.trim().eq_ignore_ascii_case("noindex")),
+        _ => false,
+    };
+
+    noindex || robots
+}
+
+#[derive(Default)]
+struct NoIndexVisitor {
+    noindex: bool,
+}
+
+impl<'a> Visitor<'a> for NoIndexVisitor {
+    fn enter_node(&mut self, context: &VisitorContext<'a>, node: &'a nodes::Node) {
+        match &node.data {
+            // Only the page's own options count, not those of files it includes
+            nodes::NodeData::Root(root) if context.depth() == 0 => {
+                self.noindex |= options_noindex(&root.options)
+            }
+            nodes::NodeData::Directive(directive) if directive.name == "meta" => {
+                self.noindex |= options_noindex(&directive.options)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Return true if a page should be left out of the sitemap.
+pub fn is_noindex(document: &nodes::Document) -> bool {
+    let mut visitor = NoIndexVisitor::default();
+    document.ast.visit(&mut visitor);
+    visitor.noindex
+}
+
+/// The path at which a page is served, with leading and trailing slashes. Index pages are
+/// served at their directory.
+pub fn page_path(page_id: &str) -> String {
+    let path = page_id.trim_matches('/');
+    let path = if path == "index" {
+        ""
+    } else {
+        path.strip_suffix("/index").unwrap_or(path)
+    };
+
+    if path.is_empty() {
+        "/".to_owned()
+    } else {
+        format!("/{}/", path)
+    }
+}
+
+/// The public URL of a page.
+fn page_url(base_url: &str, page_id: &str) -> String {
+    format!("{}{}", base_url.trim_end_matches('/'), page_path(page_id))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn render_urlset<'a>(urls: impl Iterator<Item = &'a String>) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for url in urls {
+        xml += &format!("  <url><loc>{}</loc></url>\n", escape_xml(url));
+    }
+    xml += "</urlset>\n";
+    xml
+}
+
+fn render_with_limit(base_url: &str, page_ids: &[String], limit: usize) -> Vec<(String, String)> {
+    let mut urls: Vec<String> = page_ids
+        .iter()
+        .map(|page_id| page_url(base_url, page_id))
+        .collect();
+    urls.sort();
+    urls.dedup();
+
+    if urls.len() <= limit {
+        return vec![("sitemap.xml".to_owned(), render_urlset(urls.iter()))];
+    }
+
+    // Too many URLs for one file, so split them up and list the parts in a sitemap index
+    let mut files = vec![];
+    let mut index = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for (i, chunk) in urls.chunks(limit).enumerate() {
+        let filename = format!("sitemap-{}.xml", i + 1);
+        index += &format!(
+            "  <sitemap><loc>{}</loc></sitemap>\n",
+            escape_xml(&format!("{}/{}", base_url.trim_end_matches('/'), filename))
+        );
+        files.push((filename, render_urlset(chunk.iter())));
+    }
+    index += "</sitemapindex>\n";
+    files.insert(0, ("sitemap.xml".to_owned(), index));
+
+    files
+}
+
+/// Render the sitemap of the given pages as (filename, contents) pairs. sitemap.xml is
+/// always first, and is a sitemap index if the pages do not fit in a single sitemap.
+pub fn render(base_url: &str, page_ids: &[String]) -> Vec<(String, String)> {
+    render_with_limit(base_url, page_ids, MAX_URLS_PER_SITEMAP)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn noindex() {
+        let make_doc = |root_options: bson::Bson, meta_options: bson::Bson| -> nodes::Document {
+            bson::from_bson(bson::bson!({
+                "page_id": "index",
+                "filename": "index.txt",
+                "source": "",
+                "static_assets": [],
+                "ast": {
+                    "type": "root",
+                    "position": {"start": {"line": 0}},
+                    "fileid": "index.txt",
+                    "options": root_options,
+                    "children": [{
+                        "type": "directive",
+                        "position": {"start": {"line": 0}},
+                        "domain": "",
+                        "name": "meta",
+                        "argument": [],
+                        "options": meta_options,
+                        "children": []
+                    }]
+                }
+            }))
+            .unwrap()
+        };
+
+        assert!(!is_noindex(&make_doc(
+            bson::bson!({}),
+            bson::bson!({"description": "A page"})
+        )));
+        assert!(is_noindex(&make_doc(
+            bson::bson!({"noindex": true}),
+            bson::bson!({})
+        )));
+        assert!(is_noindex(&make_doc(
+            bson::bson!({}),
+            bson::bson!({"robots": "nofollow, NoIndex"})
+        )));
+    }
+
+    #[test]
+    fn split() {
+        let page_ids: Vec<String> = ["index", "atlas/main/index", "atlas/main/a&b", "c", "d"]
+            .iter()
+            .map(|page_id| page_id.to_string())
+            .collect();
+
+        let files = render_with_limit("https://example.com/docs/", &page_ids, 10);
+        assert_eq!(files.len(), 1);
+        assert!(files[0]
+            .1
+            .contains("<loc>https://example.com/docs/atlas/main/a&amp;b/</loc>"));
+        assert!(files[0]
+            .1
+            .contains("<loc>https://example.com/docs/atlas/main/</loc>"));
+        assert!(files[0].1.contains("<loc>https://example.com/docs/</loc>"));
+
+        let files = render_with_limit("https://example.com/docs", &page_ids, 2);
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "sitemap.xml",
+                "sitemap-1.xml",
+                "sitemap-2.xml",
+                "sitemap-3.xml"
+            ]
+        );
+        assert!(files[0].1.contains("<sitemapindex"));
+        assert!(files[0]
+            .1
+            .contains("<loc>https://example.com/docs/sitemap-3.xml</loc>"));
+        assert_eq!(files[3].1.matches("<url>").count(), 1);
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::analyzer::{Analyzer, AnalyzerContext};
+use crate::bundle;
+use crate::nodes;
+
+/// Substitutions available to every project in a stitch, as inline node lists.
+#[derive(Default)]
+pub struct SubstitutionTable {
+    definitions: HashMap<String, Vec<nodes::Node>>,
+}
+
+impl SubstitutionTable {
+    pub fn new() -> Self {
+        Self {
+            definitions: HashMap::new(),
+        }
+    }
+
+    /// Create a table of plain-text substitutions, such as those given in the manifest.
+    pub fn from_text(substitutions: &HashMap<String, String>) -> Self {
+        let mut table = Self::new();
+        for (name, value) in substitutions {
+            table.definitions.insert(
+                name.to_owned(),
+                vec![nodes::Node::new(
+                    nodes::NodeData::Text(nodes::Text::new(value)),
+                    nodes::Position::default(),
+                )],
+            );
+        }
+
+        table
+    }
+
+    /// Add definitions collected from the substitutions project. Definitions given in the
+    /// manifest take precedence. A name defined more than once keeps its first definition,
+    /// in order of namespace, then page, then position within the page, whichever order
+    /// they were collected in; a message is returned for each later definition which differs.
+    pub fn merge(&mut self, collected: CollectedDefinitions) -> Vec<String> {
+        let mut definitions = collected.definitions;
+        definitions.sort_by(|a, b| {
+            (&a.namespace, &a.page, a.index).cmp(&(&b.namespace, &b.page, b.index))
+        });
+
+        let mut sources: HashMap<String, String> = HashMap::new();
+        let mut conflicts = vec![];
+        for definition in definitions {
+            match sources.get(&definition.name) {
+                None if self.definitions.contains_key(&definition.name) => (),
+                None => {
+                    sources.insert(definition.name.to_owned(), definition.source);
+                    self.definitions
+                        .insert(definition.name, definition.children);
+                }
+                Some(first) => {
+                    let existing = &self.definitions[&definition.name];
+                    if bson::to_bson(existing).ok() != bson::to_bson(&definition.children).ok() {
+                        conflicts.push(format!(
+                            "Substitution |{}| in {} conflicts with its definition in {}, which is used instead",
+                            definition.name, definition.source, first
+                        ));
+                    }
+                }
+            }
+        }
+
+        conflicts
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[nodes::Node]> {
+        self.definitions
+            .get(name)
+            .map(|children| children.as_slice())
+    }
+}
+
+/// A substitution definition, and where it was found.
+struct CollectedDefinition {
+    namespace: String,
+
+    /// The page which defines it, or which includes the file defining it
+    page: String,
+
+    /// Its position among the definitions of its page
+    index: usize,
+
+    /// The namespaced file in which it is written
+    source: String,
+    name: String,
+    children: Vec<nodes::Node>,
+}
+
+/// Substitution definitions collected from the pages of the substitutions project, which may
+/// be analyzed in any order. See [`SubstitutionTable::merge`].
+#[derive(Default)]
+pub struct CollectedDefinitions {
+    definitions: Vec<CollectedDefinition>,
+}
+
+impl CollectedDefinitions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Collect the substitution definitions of a designated project, to be merged into the global
+/// table. Definitions are collected before their project is migrated, but expanded into
+/// documents which already have been, so they are migrated under the defining project's
+/// namespace here.
+pub struct SubstitutionDefinitionPass<'a> {
+    collected: &'a Mutex<CollectedDefinitions>,
+    namespace: PathBuf,
+    n_collected: usize,
+}
+
+impl<'a> SubstitutionDefinitionPass<'a> {
+    pub fn new(collected: &'a Mutex<CollectedDefinitions>, namespace: &Path) -> Self {
+        Self {
+            collected,
+            namespace: namespace.to_owned(),
+            n_collected: 0,
+        }
+    }
+}
+
+impl<'a> Analyzer for SubstitutionDefinitionPass<'a> {
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        if let nodes::NodeData::SubstitutionDefinition(definition) = &node.data {
+            let mut children = definition.children.to_owned();
+            for child in &mut children {
+                bundle::migrate_node(child, &self.namespace);
+            }
+
+            let fileid = |fileid: Option<&nodes::FileId>| {
+                fileid
+                    .expect("Analysis started at non-root node")
+                    .as_posix()
+            };
+            // Only the order of definitions within a page matters, so counting every
+            // definition this pass has seen will do
+            self.n_collected += 1;
+            self.collected
+                .lock()
+                .unwrap()
+                .definitions
+                .push(CollectedDefinition {
+                    namespace: self.namespace.to_str().unwrap().to_owned(),
+                    page: fileid(context.get_root()),
+                    index: self.n_collected,
+                    source: format!(
+                        "{}/{}",
+                        self.namespace.to_str().unwrap(),
+                        fileid(context.get_current())
+                    ),
+                    name: definition.name.to_owned(),
+                    children,
+                });
+        }
+    }
+}
+
+/// Expand substitution references which the parser could not resolve using the global table,
+/// and warn about any that remain undefined. Definitions may use other substitutions, which
+/// are expanded in turn; a definition which uses itself, directly or not, is left unexpanded
+/// where it recurs.
+pub struct SubstitutionPass<'a> {
+    table: &'a SubstitutionTable,
+    /// For each substitution reference being traversed, the name it was expanded from, or
+    /// None if it was already resolved
+    expanding: Vec<Option<String>>,
+    diagnostics: Vec<bundle::Diagnostic>,
+}
+
+impl<'a> SubstitutionPass<'a> {
+    pub fn new(table: &'a SubstitutionTable) -> Self {
+        Self {
+            table,
+            expanding: vec![],
+            diagnostics: vec![],
+        }
+    }
+
+    fn warn(&mut self, context: &AnalyzerContext, line: i32, message: String) {
+        self.diagnostics.push(bundle::Diagnostic::new(
+            bundle::Severity::Warning,
+            line,
+            format!(
+                "{} in {}",
+                message,
+                context
+                    .get_current()
+                    .expect("Analysis started at non-root node")
+                    .as_posix()
+            ),
+        ));
+    }
+}
+
+fn reference_parts(node: &mut nodes::Node) -> Option<(&str, &mut Vec<nodes::Node>)> {
+    match &mut node.data {
+        nodes::NodeData::SubstitutionReference(reference) => {
+            Some((&reference.name, &mut reference.children))
+        }
+        nodes::NodeData::BlockSubstitutionReference(reference) => {
+            Some((&reference.name, &mut reference.children))
+        }
+        _ => None,
+    }
+}
+
+impl<'a> Analyzer for SubstitutionPass<'a> {
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        let line = node.position().line();
+        let (name, children) = match reference_parts(node) {
+            Some(parts) => parts,
+            None => return,
+        };
+
+        if !children.is_empty() {
+            self.expanding.push(None);
+            return;
+        }
+
+        let name = name.to_owned();
+        if self.expanding.iter().flatten().any(|other| *other == name) {
+            self.expanding.push(None);
+            self.warn(
+                context,
+                line,
+                format!("Substitution |{}| is defined in terms of itself", name),
+            );
+            return;
+        }
+
+        match self.table.get(&name) {
+            Some(definition) => {
+                *children = definition.to_owned();
+                self.expanding.push(Some(name));
+            }
+            None => {
+                self.expanding.push(None);
+                self.warn(context, line, format!("Undefined substitution |{}|", name));
+            }
+        }
+    }
+
+    fn exit_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) {
+        if reference_parts(node).is_some() {
+            self.expanding.pop();
+        }
+    }
+
+    fn take_diagnostics(&mut self) -> Vec<bundle::Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand() {
+        let mut definitions: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "includes/substitutions.txt",
+            "children": [
+                {
+                    "type": "substitution_definition",
+                    "position": {"start": {"line": 0}},
+                    "name": "product",
+                    "children": [
+                        {"type": "text", "position": {"start": {"line": 0}}, "value": "MongoDB Atlas"}
+                    ]
+                },
+                {
+                    "type": "substitution_definition",
+                    "position": {"start": {"line": 1}},
+                    "name": "version",
+                    "children": [
+                        {"type": "text", "position": {"start": {"line": 1}}, "value": "7.0"}
+                    ]
+                },
+                {
+                    "type": "substitution_definition",
+                    "position": {"start": {"line": 2}},
+                    "name": "install",
+                    "children": [
+                        {
+                            "type": "ref_role",
+                            "position": {"start": {"line": 2}},
+                            "children": [],
+                            "domain": "std",
+                            "name": "label",
+                            "target": "install",
+                            "flag": "",
+                            "fileid": ["tutorial/install", "std-label-install"]
+                        },
+                        {
+                            "type": "reference",
+                            "position": {"start": {"line": 2}},
+                            "children": [],
+                            "refuri": "faq"
+                        }
+                    ]
+                }
+            ]
+        }))
+        .unwrap();
+
+        let mut manifest = HashMap::new();
+        manifest.insert("version".to_owned(), "8.0".to_owned());
+        let collected = Mutex::new(CollectedDefinitions::new());
+        definitions.run_analyzer(&mut SubstitutionDefinitionPass::new(
+            &collected,
+            Path::new("docs/main"),
+        ));
+        let mut table = SubstitutionTable::from_text(&manifest);
+        assert!(table.merge(collected.into_inner().unwrap()).is_empty());
+
+        let mut ast: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "index.txt",
+            "children": [
+                {"type": "substitution_reference", "position": {"start": {"line": 0}}, "name": "product", "children": []},
+                {"type": "substitution_reference", "position": {"start": {"line": 0}}, "name": "version", "children": []},
+                {"type": "substitution_reference", "position": {"start": {"line": 3}}, "name": "undefined", "children": []},
+                {"type": "substitution_reference", "position": {"start": {"line": 0}}, "name": "install", "children": []},
+                {
+                    "type": "substitution_reference",
+                    "position": {"start": {"line": 0}},
+                    "name": "product",
+                    "children": [
+                        {"type": "text", "position": {"start": {"line": 0}}, "value": "Already resolved"}
+                    ]
+                }
+            ]
+        }))
+        .unwrap();
+        let mut pass = SubstitutionPass::new(&table);
+        ast.run_analyzer(&mut pass);
+
+        let expanded: Vec<String> = ast
+            .data
+            .children()
+            .iter()
+            .map(|node| node.get_text())
+            .collect();
+        assert_eq!(
+            expanded,
+            vec!["MongoDB Atlas", "8.0", "", "", "Already resolved"]
+        );
+
+        // Links within a definition point into the project which defined it
+        let mut links = vec![];
+        ast.for_each(&mut |node: &mut nodes::Node| match &node.data {
+            nodes::NodeData::RefRole(refrole) => {
+                links.push(refrole.fileid.as_ref().unwrap().0.to_owned())
+            }
+            nodes::NodeData::Reference(reference) => links.push(reference.refuri.to_owned()),
+            _ => (),
+        });
+        assert_eq!(links, vec!["docs/main/tutorial/install", "/docs/main/faq"]);
+
+        let diagnostics = pass.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message(),
+            "Undefined substitution |undefined| in index.txt"
+        );
+        assert_eq!(diagnostics[0].start(), 3);
+        assert_eq!(diagnostics[0].severity(), "WARNING");
+        assert!(pass.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn cycles() {
+        let reference = |name: &str| {
+            bson::bson!({
+                "type": "substitution_reference",
+                "position": {"start": {"line": 0}},
+                "name": name,
+                "children": []
+            })
+        };
+        let text = |value: &str| bson::bson!({"type": "text", "position": {"start": {"line": 0}}, "value": value});
+        let definition = |name: &str, children: Vec<bson::Bson>| {
+            bson::bson!({
+                "type": "substitution_definition",
+                "position": {"start": {"line": 0}},
+                "name": name,
+                "children": children
+            })
+        };
+
+        let mut definitions: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "includes/substitutions.txt",
+            "children": [
+                definition("self", vec![text("a"), reference("self")]),
+                definition("ping", vec![text("b"), reference("pong")]),
+                definition("pong", vec![text("c"), reference("ping")]),
+                definition("nested", vec![reference("product"), reference("product")]),
+                definition("product", vec![text("Atlas")]),
+            ]
+        }))
+        .unwrap();
+        let collected = Mutex::new(CollectedDefinitions::new());
+        definitions.run_analyzer(&mut SubstitutionDefinitionPass::new(
+            &collected,
+            Path::new("docs/main"),
+        ));
+        let mut table = SubstitutionTable::new();
+        assert!(table.merge(collected.into_inner().unwrap()).is_empty());
+
+        let mut ast: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "index.txt",
+            "children": [reference("self"), reference("ping"), reference("nested")]
+        }))
+        .unwrap();
+        let mut pass = SubstitutionPass::new(&table);
+        ast.run_analyzer(&mut pass);
+
+        // Each cycle is expanded once, and a substitution used twice is not a cycle
+        let expanded: Vec<String> = ast
+            .data
+            .children()
+            .iter()
+            .map(|node| node.get_text())
+            .collect();
+        assert_eq!(expanded, vec!["a", "bc", "AtlasAtlas"]);
+
+        let messages: Vec<String> = pass
+            .take_diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.message().to_owned())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Substitution |self| is defined in terms of itself in index.txt",
+                "Substitution |ping| is defined in terms of itself in index.txt",
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicting_definitions() {
+        let page = |fileid: &str, value: &str| -> nodes::Node {
+            bson::from_bson(bson::bson!({
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "fileid": fileid,
+                "children": [
+                    {
+                        "type": "substitution_definition",
+                        "position": {"start": {"line": 0}},
+                        "name": "version",
+                        "children": [
+                            {"type": "text", "position": {"start": {"line": 0}}, "value": value}
+                        ]
+                    },
+                    {
+                        "type": "substitution_definition",
+                        "position": {"start": {"line": 1}},
+                        "name": "product",
+                        "children": [
+                            {"type": "text", "position": {"start": {"line": 1}}, "value": "Atlas"}
+                        ]
+                    }
+                ]
+            }))
+            .unwrap()
+        };
+
+        // Whichever order the pages are analyzed in, the first page's definition is used
+        for reverse in [false, true] {
+            let collected = Mutex::new(CollectedDefinitions::new());
+            let mut pages = vec![page("a.txt", "7.0"), page("b.txt", "8.0")];
+            if reverse {
+                pages.reverse();
+            }
+            for mut page in pages {
+                page.run_analyzer(&mut SubstitutionDefinitionPass::new(
+                    &collected,
+                    Path::new("docs/main"),
+                ));
+            }
+
+            let mut table = SubstitutionTable::new();
+            let conflicts = table.merge(collected.into_inner().unwrap());
+            assert_eq!(
+                conflicts,
+                vec!["Substitution |version| in docs/main/b.txt conflicts with its definition in docs/main/a.txt, which is used instead"]
+            );
+            assert_eq!(table.get("version").unwrap()[0].get_text(), "7.0");
+            assert_eq!(table.get("product").unwrap()[0].get_text(), "Atlas");
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::analyzer::{Analyzer, AnalyzerContext};
+use crate::nodes;
+use crate::target_database;
+
+/// How long one phase of a stitch took.
+#[derive(Debug, Serialize)]
+pub struct PhaseTiming {
+    pub name: String,
+    pub seconds: f64,
+}
+
+impl PhaseTiming {
+    pub fn new(name: &str, duration: Duration) -> Self {
+        Self {
+            name: name.to_owned(),
+            seconds: duration.as_secs_f64(),
+        }
+    }
+}
+
+/// What a stitch did, for logging and for saving as JSON.
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub documents: usize,
+    pub assets_written: usize,
+    /// Assets skipped because an identical asset had already been written
+    pub assets_deduplicated: usize,
+    pub diagnostics: usize,
+    pub targets_defined: usize,
+    /// Refs which reach a page and anchor in the output, or an external url
+    pub refs_resolved: usize,
+    pub refs_unresolved: usize,
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl Summary {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn log(&self) {
+        log::info!(
+            "Wrote {} documents, {} assets ({} duplicates skipped) and {} diagnostics",
+            self.documents,
+            self.assets_written,
+            self.assets_deduplicated,
+            self.diagnostics
+        );
+        log::info!(
+            "{} targets defined; {} refs resolved, {} unresolved",
+            self.targets_defined,
+            self.refs_resolved,
+            self.refs_unresolved
+        );
+        for phase in &self.phases {
+            log::info!("{}: {:.2}s", phase.name, phase.seconds);
+        }
+    }
+}
+
+/// Count the refs in each migrated document which reach a page and anchor in the stitched
+/// output or point at an external url, and those which do not.
+pub struct RefCountPass<'a> {
+    db: &'a target_database::TargetDatabase,
+    summary: &'a Mutex<Summary>,
+    resolved: usize,
+    unresolved: usize,
+}
+
+impl<'a> RefCountPass<'a> {
+    pub fn new(db: &'a target_database::TargetDatabase, summary: &'a Mutex<Summary>) -> Self {
+        Self {
+            db,
+            summary,
+            resolved: 0,
+            unresolved: 0,
+        }
+    }
+}
+
+impl<'a> Analyzer for RefCountPass<'a> {
+    fn enter_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) {
+        if let nodes::NodeData::RefRole(refrole) = &node.data {
+            let resolved = refrole.url.is_some()
+                || refrole.fileid.as_ref().is_some_and(|(fileid, html_id)| {
+                    self.db.resolve_page(fileid).is_some_and(|page| {
+                        html_id.is_empty() || self.db.has_anchor(&page, html_id)
+                    })
+                });
+
+            if resolved {
+                self.resolved += 1;
+            } else {
+                self.unresolved += 1;
+            }
+        }
+    }
+
+    // Count locally, and only take the lock once per page
+    fn exit_page(&mut self, _context: &AnalyzerContext, _page: &nodes::Document) {
+        let mut summary = self.summary.lock().unwrap();
+        summary.refs_resolved += std::mem::take(&mut self.resolved);
+        summary.refs_unresolved += std::mem::take(&mut self.unresolved);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::output::OutputSink;
+    use crate::{bundle, bundle_set, config, output, synthetic};
+
+    #[test]
+    fn summarize_stitch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let generator = synthetic::Generator {
+            pages: 5,
+            assets: 2,
+            ..synthetic::Generator::new("synthetic")
+        };
+        generator
+            .write(output::DirectorySink::create(&root.join("input")).unwrap())
+            .unwrap();
+
+        // Another project holds a ref to an external url and a copy of one of the assets
+        let asset = format!("{:064x}", 0);
+        let page = bson::doc! {
+            "page_id": "links",
+            "filename": "links.txt",
+            "ast": {
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "fileid": "links.txt",
+                "children": [{
+                    "type": "ref_role",
+                    "position": {"start": {"line": 0}},
+                    "domain": "std",
+                    "name": "ref",
+                    "target": "external",
+                    "flag": "",
+                    "children": [],
+                    "url": "https://www.mongodb.com/",
+                }],
+            },
+            "source": "",
+            "static_assets": [],
+        };
+        let mut other = output::DirectorySink::create(&root.join("other")).unwrap();
+        other.start_file("site.bson").unwrap();
+        other
+            .write_all(&bson::to_vec(&bundle::SiteMetadata::new("other", "main")).unwrap())
+            .unwrap();
+        other.start_file("documents/links.bson").unwrap();
+        other.write_all(&bson::to_vec(&page).unwrap()).unwrap();
+        other.start_file(&format!("assets/{asset}")).unwrap();
+        other
+            .write_all(&std::fs::read(root.join("input/assets").join(&asset)).unwrap())
+            .unwrap();
+        other.finish().unwrap();
+
+        let inputs = ["input", "other"].map(|name| bundle::Bundle::open(root.join(name)).unwrap());
+        let mut bundles = bundle_set::BundleSet::new(inputs.into_iter(), config::Config::default());
+        bundles.link().unwrap();
+        // Splicing again replaces the counts of the previous splice rather than adding to them
+        for name in ["first", "second"] {
+            bundles
+                .splice(
+                    &bundle::SiteMetadata::new("mongodb", "main"),
+                    None,
+                    None,
+                    bundle::OutputFormat::Bson,
+                    output::DirectorySink::create(&root.join(name)).unwrap(),
+                )
+                .unwrap();
+        }
+
+        let summary = bundles.take_summary();
+        assert_eq!(
+            (
+                summary.documents,
+                summary.assets_written,
+                summary.diagnostics
+            ),
+            (6, 2, 5)
+        );
+        assert_eq!(summary.assets_deduplicated, 1);
+        assert_eq!((summary.refs_resolved, summary.refs_unresolved), (101, 0));
+        // Ten labels on each of the five pages
+        assert_eq!(summary.targets_defined, 50);
+    }
+}
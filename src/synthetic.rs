@@ -0,0 +1,258 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use crate::bundle;
+use crate::nodes;
+use crate::output;
+
+/// Size of each generated asset, in bytes.
+const ASSET_SIZE: usize = 4096;
+
+/// Generates synthetic bundles of a given shape, for benchmarking and profiling. Output is
+/// deterministic: the same settings always produce the same bundle.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    pub project: String,
+    pub branch: String,
+    pub pages: usize,
+    /// Targets defined on each page, each under its own label.
+    pub targets_per_page: usize,
+    /// Refs on each page, spread across the targets of other pages. Refs need targets to
+    /// point at and another page to find them on.
+    pub refs_per_page: usize,
+    /// Distinct assets in the bundle. Each page references one of them.
+    pub assets: usize,
+    pub diagnostics_per_page: usize,
+}
+
+impl Generator {
+    pub fn new(project: impl Into<String>) -> Self {
+        Self {
+            project: project.into(),
+            branch: "main".to_owned(),
+            pages: 100,
+            targets_per_page: 10,
+            refs_per_page: 20,
+            assets: 10,
+            diagnostics_per_page: 1,
+        }
+    }
+
+    fn page_name(page: usize) -> String {
+        format!("page-{page}")
+    }
+
+    fn target_id(page: usize, target: usize) -> String {
+        format!("page-{page}-target-{target}")
+    }
+
+    fn asset_checksum(asset: usize) -> String {
+        format!("{asset:064x}")
+    }
+
+    fn text(value: String) -> nodes::Node {
+        nodes::Node::new(
+            nodes::NodeData::Text(nodes::Text::new(value)),
+            nodes::Position::default(),
+        )
+    }
+
+    fn node(data: nodes::NodeData) -> nodes::Node {
+        nodes::Node::new(data, nodes::Position::default())
+    }
+
+    /// Build a single page of the bundle.
+    pub fn document(&self, page: usize) -> nodes::Document {
+        let name = Self::page_name(page);
+        let mut children = vec![Self::node(nodes::NodeData::Heading(nodes::Heading::new(
+            name.to_owned(),
+            vec![Self::text(format!("Page {page}"))],
+        )))];
+
+        for target in 0..self.targets_per_page {
+            let identifier = nodes::TargetIdentifier::new(
+                vec![Self::target_id(page, target)],
+                vec![Self::text(format!("Target {target} of page {page}"))],
+            );
+            children.push(Self::node(nodes::NodeData::Target(nodes::Target::new(
+                "std",
+                "label",
+                vec![Self::node(nodes::NodeData::TargetIdentifier(identifier))],
+            ))));
+        }
+
+        if self.targets_per_page > 0 {
+            let refs = (0..self.refs_per_page).map(|r| {
+                let mut other_page = (page * 31 + r * 17 + 1) % self.pages;
+                if other_page == page {
+                    other_page = (other_page + 1) % self.pages;
+                }
+                let target_id = Self::target_id(other_page, r % self.targets_per_page);
+                let html_id = format!("std-label-{target_id}");
+                Self::node(nodes::NodeData::RefRole(nodes::RefRole::new(
+                    "std",
+                    "ref",
+                    target_id,
+                    Some((Self::page_name(other_page), html_id)),
+                )))
+            });
+            children.push(Self::node(nodes::NodeData::Paragraph(
+                nodes::Paragraph::new(refs.collect()),
+            )));
+        }
+
+        children.push(Self::node(nodes::NodeData::Paragraph(
+            nodes::Paragraph::new(vec![Self::text(format!(
+                "Synthetic content for page {page} of {}.",
+                self.project
+            ))]),
+        )));
+
+        let fileid = nodes::FileId::from(PathBuf::from(format!("{name}.txt")));
+        let ast = Self::node(nodes::NodeData::Root(nodes::Root::new(
+            fileid.clone(),
+            vec![Self::node(nodes::NodeData::Section(nodes::Section::new(
+                children,
+            )))],
+        )));
+
+        let static_assets = if self.assets > 0 {
+            let asset = page % self.assets;
+            vec![nodes::StaticAssetReference::new(
+                Self::asset_checksum(asset),
+                format!("/images/asset-{asset}.png"),
+            )]
+        } else {
+            vec![]
+        };
+
+        nodes::Document::new(name, fileid, ast, static_assets)
+    }
+
+    /// Check that these settings describe a bundle which can be generated.
+    pub fn validate(&self) -> Result<()> {
+        if self.refs_per_page > 0 && self.targets_per_page == 0 {
+            bail!("Cannot generate refs without targets: refs_per_page requires targets_per_page");
+        }
+        if self.refs_per_page > 0 && self.pages < 2 {
+            bail!("Cannot generate refs to other pages with fewer than two pages");
+        }
+
+        Ok(())
+    }
+
+    /// Write the bundle to an output sink.
+    pub fn write(&self, mut out: impl output::OutputSink) -> Result<()> {
+        self.validate()?;
+
+        out.start_file("site.bson")?;
+        out.write_all(&bson::to_vec(&bundle::SiteMetadata::new(
+            &self.project,
+            &self.branch,
+        ))?)?;
+
+        for page in 0..self.pages {
+            let name = Self::page_name(page);
+            out.start_file(&format!("documents/{name}.bson"))?;
+            out.write_all(&bson::to_vec(&self.document(page))?)?;
+
+            if self.diagnostics_per_page > 0 {
+                let diagnostics = (0..self.diagnostics_per_page)
+                    .map(|i| {
+                        bundle::Diagnostic::new(
+                            bundle::Severity::Warning,
+                            i as i32,
+                            format!("Synthetic diagnostic {i}"),
+                        )
+                    })
+                    .collect();
+                out.start_file(&format!("diagnostics/{name}.bson"))?;
+                out.write_all(&bson::to_vec(&bundle::Diagnostics { diagnostics })?)?;
+            }
+        }
+
+        for asset in 0..self.assets {
+            out.start_file(&format!("assets/{}", Self::asset_checksum(asset)))?;
+            let data: Vec<u8> = (0..ASSET_SIZE).map(|i| (i + asset) as u8).collect();
+            out.write_all(&data)?;
+        }
+
+        out.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn generate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let generator = Generator {
+            pages: 5,
+            assets: 2,
+            ..Generator::new("synthetic")
+        };
+        generator
+            .write(output::DirectorySink::create(root).unwrap())
+            .unwrap();
+
+        let mut bundle = bundle::Bundle::open(root).unwrap();
+        assert_eq!(bundle.metadata.project(), "synthetic");
+        let (mut documents, mut assets, mut diagnostics) = (0, 0, 0);
+        for element in &mut bundle {
+            match element.unwrap().data {
+                bundle::BundleElementData::Document(mut document) => {
+                    documents += 1;
+                    assert!(document.title().unwrap().starts_with("Page "));
+
+                    // Every ref points at another page
+                    let mut refs = 0;
+                    document.ast.for_each(&mut |node: &mut nodes::Node| {
+                        if let nodes::NodeData::RefRole(refrole) = &node.data {
+                            refs += 1;
+                            assert_ne!(refrole.fileid.as_ref().unwrap().0, document.page_id);
+                        }
+                    });
+                    assert_eq!(refs, 20);
+                }
+                bundle::BundleElementData::Asset(asset) => {
+                    assets += 1;
+                    assert_eq!(asset.len(), ASSET_SIZE);
+                }
+                bundle::BundleElementData::Diagnostics(_) => diagnostics += 1,
+            }
+        }
+        assert_eq!((documents, assets, diagnostics), (5, 2, 5));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let tmp = tempfile::tempdir().unwrap();
+        let no_targets = Generator {
+            targets_per_page: 0,
+            ..Generator::new("synthetic")
+        };
+        let one_page = Generator {
+            pages: 1,
+            ..Generator::new("synthetic")
+        };
+        for generator in [no_targets, one_page] {
+            let sink = output::DirectorySink::create(&tmp.path().join("output")).unwrap();
+            assert!(generator.write(sink).is_err());
+        }
+
+        let no_refs = Generator {
+            pages: 1,
+            targets_per_page: 0,
+            refs_per_page: 0,
+            ..Generator::new("synthetic")
+        };
+        let sink = output::DirectorySink::create(&tmp.path().join("output")).unwrap();
+        no_refs.write(sink).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
 
 use lazy_static::lazy_static;
 
@@ -12,31 +15,105 @@ lazy_static! {
 /// Normalize targets to allow easy matching against the target
 /// database: normalize whitespace.
 fn normalize_target(target: &str) -> Cow<'_, str> {
-    return PAT_WHITESPACE.replace_all(target, " ");
+    PAT_WHITESPACE.replace_all(target, " ")
 }
 
 struct LocalDefinition {
     canonical_name: String,
     fileid: nodes::FileId,
+    source_fileid: nodes::FileId,
     title: Vec<nodes::Node>,
     html5_id: String,
 }
 
+/// A target definition found by [`TargetDatabase::get`].
 pub struct InternalResult {
     result: (String, String),
+    source_fileid: String,
     canonical_name: String,
     title: Vec<nodes::Node>,
 }
 
+impl InternalResult {
+    /// The page defining the target, without its file extension.
+    pub fn page(&self) -> &str {
+        &self.result.0
+    }
+
+    pub fn html_id(&self) -> &str {
+        &self.result.1
+    }
+
+    /// The file the target was written in, which differs from the page for targets in
+    /// included files.
+    pub fn source_fileid(&self) -> &str {
+        &self.source_fileid
+    }
+
+    pub fn canonical_name(&self) -> &str {
+        &self.canonical_name
+    }
+
+    pub fn title(&self) -> &[nodes::Node] {
+        &self.title
+    }
+}
+
+#[derive(Default)]
 pub struct TargetDatabase {
     local_definitions: HashMap<String, Vec<LocalDefinition>>,
+    pages: HashSet<String>,
+    anchors: HashMap<String, HashSet<String>>,
 }
 
 impl TargetDatabase {
     pub fn new() -> Self {
         Self {
             local_definitions: HashMap::new(),
+            pages: HashSet::new(),
+            anchors: HashMap::new(),
+        }
+    }
+
+    /// The number of target names defined across every project.
+    pub fn n_targets(&self) -> usize {
+        self.local_definitions.values().map(|defs| defs.len()).sum()
+    }
+
+    /// Record that a page exists in the stitched output, by its namespaced page id.
+    pub fn define_page(&mut self, page_id: String) {
+        self.pages.insert(page_id);
+    }
+
+    pub fn has_page(&self, page_id: &str) -> bool {
+        self.pages.contains(page_id)
+    }
+
+    /// Find the page that a path refers to. Directory-style paths such as "foo/" may name
+    /// either the page "foo" or the index page beneath it.
+    pub fn resolve_page(&self, path: &str) -> Option<String> {
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return None;
         }
+
+        [path.to_owned(), format!("{path}/index")]
+            .into_iter()
+            .find(|page_id| self.has_page(page_id))
+    }
+
+    /// Record an HTML id that links may point at within a namespaced page.
+    pub fn define_anchor(&mut self, page_id: &str, html_id: &str) {
+        self.anchors
+            .entry(page_id.to_owned())
+            .or_default()
+            .insert(html_id.to_owned());
+    }
+
+    pub fn has_anchor(&self, page_id: &str, html_id: &str) -> bool {
+        self.anchors
+            .get(page_id)
+            .is_some_and(|anchors| anchors.contains(html_id))
     }
 
     pub fn get(&self, key: &str) -> Vec<InternalResult> {
@@ -51,6 +128,7 @@ impl TargetDatabase {
         for def in matches {
             results.push(InternalResult {
                 result: (def.fileid.without_known_suffix(), def.html5_id.to_owned()),
+                source_fileid: def.source_fileid.as_posix(),
                 canonical_name: def.canonical_name.to_owned(),
                 title: def.title.to_owned(),
             })
@@ -59,12 +137,14 @@ impl TargetDatabase {
         results
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn define_local_target(
         &mut self,
         domain: &str,
         name: &str,
         targets: &[&str],
         pageid: &nodes::FileId,
+        source_fileid: &nodes::FileId,
         title: &[nodes::Node],
         html5_id: &str,
     ) {
@@ -81,15 +161,116 @@ impl TargetDatabase {
         for target in targets {
             let target = normalize_target(target);
             let key = format!("{domain}:{name}:{target}");
-            self.local_definitions
-                .entry(key)
-                .or_default()
-                .push(LocalDefinition {
-                    canonical_name: canonical_name.to_owned(),
-                    fileid: pageid.to_owned(),
-                    title: title.to_owned(),
-                    html5_id: html5_id.to_owned(),
-                })
+            let definitions = self.local_definitions.entry(key).or_default();
+
+            // Html ids are unique within a page, so a page analyzed again redefines the same
+            // targets rather than adding new ones
+            if definitions
+                .iter()
+                .any(|def| def.fileid.path == pageid.path && def.html5_id == html5_id)
+            {
+                continue;
+            }
+
+            definitions.push(LocalDefinition {
+                canonical_name: canonical_name.to_owned(),
+                fileid: pageid.to_owned(),
+                source_fileid: source_fileid.to_owned(),
+                title: title.to_owned(),
+                html5_id: html5_id.to_owned(),
+            })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::analyzer::TargetPass1;
+    use crate::html_ids::HtmlIdPass;
+    use crate::passes::PassManager;
+
+    #[test]
+    fn included_targets() {
+        let mut doc: nodes::Document = bson::from_bson(bson::bson!({
+            "page_id": "project/index",
+            "filename": "index.txt",
+            "source": "",
+            "static_assets": [],
+            "ast": {
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "fileid": "index.txt",
+                "children": [
+                    nodes::tests::make_target(0, "overview"),
+                    {
+                        "type": "root",
+                        "position": {"start": {"line": 1}},
+                        "fileid": "includes/install.rst",
+                        "children": [nodes::tests::make_target(0, "install")]
+                    },
+                    nodes::tests::make_target(2, "overview")
+                ]
+            }
+        }))
+        .unwrap();
+        let mut faq: nodes::Document = bson::from_bson(bson::bson!({
+            "page_id": "project/faq",
+            "filename": "faq.txt",
+            "source": "",
+            "static_assets": [],
+            "ast": {
+                "type": "root",
+                "position": {"start": {"line": 0}},
+                "fileid": "faq.txt",
+                "children": [nodes::tests::make_target(0, "overview")]
+            }
+        }))
+        .unwrap();
+
+        let db = Mutex::new(TargetDatabase::new());
+        let mut passes = PassManager::new();
+        passes
+            .register("html_ids", &[], HtmlIdPass::new("project", None))
+            .register("targets", &["html_ids"], TargetPass1::new(&db));
+        passes.schedule().unwrap();
+        passes.run(&mut doc);
+        // Running the page again assigns the same ids, so no target is defined twice
+        passes.run(&mut doc);
+        // The same passes start afresh on each page, so ids taken on another do not count
+        passes.run(&mut faq);
+        drop(passes);
+        let db = db.into_inner().unwrap();
+
+        let install = db.get("std:label:install");
+        assert_eq!(install.len(), 1);
+        assert_eq!(install[0].page(), "index");
+        assert_eq!(install[0].source_fileid(), "includes/install.rst");
+        assert_eq!(install[0].canonical_name(), "install");
+        assert!(install[0].title().is_empty());
+
+        let overview: Vec<(String, String)> = db
+            .get("std:label:overview")
+            .into_iter()
+            .map(|result| {
+                (
+                    result.source_fileid().to_owned(),
+                    result.html_id().to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            overview,
+            [
+                ("index.txt", "std-label-overview"),
+                ("index.txt", "std-label-overview-1"),
+                ("faq.txt", "std-label-overview"),
+            ]
+            .map(|(fileid, html_id)| (fileid.to_owned(), html_id.to_owned()))
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{Analyzer, AnalyzerContext};
+use crate::nodes;
+
+/// A single entry in the merged site navigation tree.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TocTreeNode {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    pub children: Vec<TocTreeNode>,
+}
+
+#[derive(Default)]
+struct PageTocTree {
+    title: Option<String>,
+    entries: Vec<nodes::TocTreeDirectiveEntry>,
+}
+
+struct ProjectTocTree {
+    project: String,
+    pages: HashMap<String, PageTocTree>,
+}
+
+/// Turn a toctree slug such as "/reference/foo" into the page name it refers to.
+fn slug_to_page(slug: &str) -> &str {
+    let page = slug.trim_matches('/');
+    if page.is_empty() {
+        "index"
+    } else {
+        page
+    }
+}
+
+/// The toctree entries declared by every page of every project in a stitch. Each branch of
+/// a project has its own tree, keyed by its namespace.
+#[derive(Default)]
+pub struct TocTreeDatabase {
+    namespaces: BTreeMap<String, ProjectTocTree>,
+}
+
+impl TocTreeDatabase {
+    pub fn new() -> Self {
+        Self {
+            namespaces: BTreeMap::new(),
+        }
+    }
+
+    fn get_page(&mut self, project: &str, namespace: &str, page: &str) -> &mut PageTocTree {
+        self.namespaces
+            .entry(namespace.to_owned())
+            .or_insert_with(|| ProjectTocTree {
+                project: project.to_owned(),
+                pages: HashMap::new(),
+            })
+            .pages
+            .entry(page.to_owned())
+            .or_default()
+    }
+
+    /// The namespaces a reference to a project resolves to: the given namespace, if it names
+    /// one such as "atlas/v2", and otherwise every branch of the named project.
+    fn resolve(&self, reference: &str) -> Vec<&str> {
+        if let Some((namespace, _)) = self.namespaces.get_key_value(reference) {
+            return vec![namespace.as_str()];
+        }
+
+        self.namespaces
+            .iter()
+            .filter(|(_, toctree)| toctree.project == reference)
+            .map(|(namespace, _)| namespace.as_str())
+            .collect()
+    }
+
+    /// Build a single navigation tree rooted at the umbrella project's index page, grafting
+    /// in the tree of each project referenced through a toctree `ref_project` entry. The
+    /// umbrella may be given as a project with a single branch, or as a namespace. A
+    /// reference to a project with several branches grafts in each of them, in order.
+    pub fn merge(&self, umbrella: &str) -> anyhow::Result<TocTreeNode> {
+        let namespace = match self.resolve(umbrella)[..] {
+            [namespace] => namespace,
+            [] => anyhow::bail!("Umbrella project {} is not part of this stitch", umbrella),
+            ref namespaces => anyhow::bail!(
+                "Umbrella project {} has several branches; choose one of {}",
+                umbrella,
+                namespaces.join(", ")
+            ),
+        };
+
+        Ok(self.build_project(namespace, &mut vec![]))
+    }
+
+    fn build_project(&self, namespace: &str, namespace_stack: &mut Vec<String>) -> TocTreeNode {
+        let toctree = &self.namespaces[namespace];
+        namespace_stack.push(namespace.to_owned());
+
+        let mut visited_pages = HashSet::new();
+        visited_pages.insert("index".to_owned());
+        let children =
+            self.build_page_children(namespace, "index", namespace_stack, &mut visited_pages);
+
+        namespace_stack.pop();
+        TocTreeNode {
+            title: toctree
+                .pages
+                .get("index")
+                .and_then(|page| page.title.clone()),
+            slug: Some(format!("/{}/index", namespace)),
+            url: None,
+            children,
+        }
+    }
+
+    fn build_page_children(
+        &self,
+        namespace: &str,
+        page: &str,
+        namespace_stack: &mut Vec<String>,
+        visited_pages: &mut HashSet<String>,
+    ) -> Vec<TocTreeNode> {
+        let toctree = &self.namespaces[namespace];
+        let page_toctree = if let Some(page_toctree) = toctree.pages.get(page) {
+            page_toctree
+        } else {
+            return vec![];
+        };
+
+        let mut children = vec![];
+        for entry in &page_toctree.entries {
+            if let Some(ref_project) = &entry.ref_project {
+                let ref_namespaces = self.resolve(ref_project);
+                if ref_namespaces.is_empty() {
+                    log::warn!(
+                        "Toctree in {}/{} references project {}, which is not part of this stitch",
+                        namespace,
+                        page,
+                        ref_project
+                    );
+                    continue;
+                }
+
+                for ref_namespace in ref_namespaces {
+                    if namespace_stack.iter().any(|other| other == ref_namespace) {
+                        log::warn!(
+                            "Toctree cycle: {} references {}, which is already an ancestor",
+                            namespace,
+                            ref_namespace
+                        );
+                        continue;
+                    }
+
+                    let mut grafted = self.build_project(ref_namespace, namespace_stack);
+                    if entry.title.is_some() {
+                        grafted.title = entry.title.clone();
+                    }
+                    children.push(grafted);
+                }
+            } else if let Some(slug) = &entry.slug {
+                let child_page = slug_to_page(slug);
+                let child_children = if visited_pages.insert(child_page.to_owned()) {
+                    self.build_page_children(namespace, child_page, namespace_stack, visited_pages)
+                } else {
+                    vec![]
+                };
+
+                children.push(TocTreeNode {
+                    title: entry.title.clone().or_else(|| {
+                        toctree
+                            .pages
+                            .get(child_page)
+                            .and_then(|page| page.title.clone())
+                    }),
+                    slug: Some(format!("/{}/{}", namespace, child_page)),
+                    url: None,
+                    children: child_children,
+                });
+            } else if let Some(url) = &entry.url {
+                children.push(TocTreeNode {
+                    title: entry.title.clone(),
+                    slug: None,
+                    url: Some(url.to_owned()),
+                    children: vec![],
+                });
+            }
+        }
+
+        children
+    }
+}
+
+/// Record the toctree entries and title of each page in a project.
+pub struct TocTreePass<'a> {
+    db: &'a Mutex<TocTreeDatabase>,
+    project: String,
+    namespace: String,
+}
+
+impl<'a> TocTreePass<'a> {
+    pub fn new(db: &'a Mutex<TocTreeDatabase>, project: &str, namespace: &str) -> Self {
+        Self {
+            db,
+            project: project.to_owned(),
+            namespace: namespace.to_owned(),
+        }
+    }
+}
+
+impl<'a> Analyzer for TocTreePass<'a> {
+    fn enter_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) {
+        let page = context
+            .get_root()
+            .expect("Analysis started at non-root node")
+            .without_known_suffix();
+
+        match &node.data {
+            nodes::NodeData::Heading(_) => {
+                let mut db = self.db.lock().unwrap();
+                let page_toctree = db.get_page(&self.project, &self.namespace, &page);
+                if page_toctree.title.is_none() {
+                    page_toctree.title = Some(node.get_text());
+                }
+            }
+            nodes::NodeData::Directive(directive) if directive.name == "toctree" => {
+                let mut db = self.db.lock().unwrap();
+                let page_toctree = db.get_page(&self.project, &self.namespace, &page);
+                if let Some(entries) = &directive.entries {
+                    page_toctree.entries.extend(entries.iter().cloned());
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn make_page(
+        db: &Mutex<TocTreeDatabase>,
+        project: &str,
+        fileid: &str,
+        title: &str,
+        entries: bson::Bson,
+    ) {
+        make_branch_page(db, project, "main", fileid, title, entries);
+    }
+
+    fn make_branch_page(
+        db: &Mutex<TocTreeDatabase>,
+        project: &str,
+        branch: &str,
+        fileid: &str,
+        title: &str,
+        entries: bson::Bson,
+    ) {
+        let mut ast: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": fileid,
+            "children": [
+                {
+                    "type": "heading",
+                    "position": {"start": {"line": 0}},
+                    "id": "heading",
+                    "children": [
+                        {"type": "text", "position": {"start": {"line": 0}}, "value": title}
+                    ]
+                },
+                {
+                    "type": "directive",
+                    "position": {"start": {"line": 1}},
+                    "domain": "",
+                    "name": "toctree",
+                    "argument": [],
+                    "children": [],
+                    "entries": entries
+                }
+            ]
+        }))
+        .unwrap();
+
+        let mut pass = TocTreePass::new(db, project, &format!("{project}/{branch}"));
+        ast.run_analyzer(&mut pass);
+    }
+
+    #[test]
+    fn merge() {
+        let db = Mutex::new(TocTreeDatabase::new());
+        make_page(
+            &db,
+            "mongodb",
+            "index.txt",
+            "MongoDB Docs",
+            bson::bson!([
+                {"slug": "/intro"},
+                {"ref_project": "atlas", "title": "Atlas"},
+                {"url": "https://example.com", "title": "External"},
+                {"ref_project": "missing"}
+            ]),
+        );
+        make_page(&db, "mongodb", "intro.txt", "Introduction", bson::bson!([]));
+        make_page(
+            &db,
+            "atlas",
+            "index.txt",
+            "Atlas Home",
+            bson::bson!([{"slug": "/getting-started", "title": "Get Started"}, {"ref_project": "mongodb"}]),
+        );
+
+        let merged = db.lock().unwrap().merge("mongodb").unwrap();
+        let expected: TocTreeNode = bson::from_bson(bson::bson!({
+            "title": "MongoDB Docs",
+            "slug": "/mongodb/main/index",
+            "children": [
+                {"title": "Introduction", "slug": "/mongodb/main/intro", "children": []},
+                {
+                    "title": "Atlas",
+                    "slug": "/atlas/main/index",
+                    "children": [
+                        {"title": "Get Started", "slug": "/atlas/main/getting-started", "children": []}
+                    ]
+                },
+                {"title": "External", "url": "https://example.com", "children": []}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(merged, expected);
+
+        assert!(db.lock().unwrap().merge("nonexistent").is_err());
+    }
+
+    #[test]
+    fn branches() {
+        let db = Mutex::new(TocTreeDatabase::new());
+        make_page(
+            &db,
+            "mongodb",
+            "index.txt",
+            "MongoDB Docs",
+            bson::bson!([
+                {"ref_project": "atlas"},
+                {"ref_project": "atlas/v2", "title": "Latest Atlas"}
+            ]),
+        );
+        make_branch_page(
+            &db,
+            "atlas",
+            "v2",
+            "index.txt",
+            "Atlas 2",
+            bson::bson!([{"slug": "/new"}]),
+        );
+        make_branch_page(&db, "atlas", "v2", "new.txt", "New", bson::bson!([]));
+        make_branch_page(
+            &db,
+            "atlas",
+            "v1",
+            "index.txt",
+            "Atlas 1",
+            bson::bson!([{"slug": "/legacy"}]),
+        );
+        make_branch_page(&db, "atlas", "v1", "legacy.txt", "Legacy", bson::bson!([]));
+
+        // Each branch keeps its own tree. A reference to the project grafts in every branch,
+        // in order, and a reference to a namespace only that branch.
+        let v1: TocTreeNode = bson::from_bson(bson::bson!({
+            "title": "Atlas 1",
+            "slug": "/atlas/v1/index",
+            "children": [{"title": "Legacy", "slug": "/atlas/v1/legacy", "children": []}]
+        }))
+        .unwrap();
+        let v2: TocTreeNode = bson::from_bson(bson::bson!({
+            "title": "Atlas 2",
+            "slug": "/atlas/v2/index",
+            "children": [{"title": "New", "slug": "/atlas/v2/new", "children": []}]
+        }))
+        .unwrap();
+        let db = db.into_inner().unwrap();
+        let merged = db.merge("mongodb").unwrap();
+        assert_eq!(
+            merged.children,
+            vec![
+                v1.clone(),
+                v2.clone(),
+                TocTreeNode {
+                    title: Some("Latest Atlas".to_owned()),
+                    ..v2
+                }
+            ]
+        );
+
+        assert_eq!(db.merge("atlas/v1").unwrap(), v1);
+        assert!(db.merge("atlas").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use crate::analyzer::{Action, AnalyzerContext, Transform};
+use crate::nodes;
+
+/// Remove comment nodes, which are never rendered.
+pub struct StripComments;
+
+impl Transform for StripComments {
+    fn transform_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) -> Action {
+        if let nodes::NodeData::Comment(_) = node.data {
+            Action::Remove
+        } else {
+            Action::Keep
+        }
+    }
+}
+
+/// Replace the named directives with their contents, e.g. to unconditionally include the
+/// body of `only` directives.
+pub struct UnwrapDirectives<'a> {
+    names: &'a [String],
+}
+
+impl<'a> UnwrapDirectives<'a> {
+    pub fn new(names: &'a [String]) -> Self {
+        Self { names }
+    }
+}
+
+impl<'a> Transform for UnwrapDirectives<'a> {
+    fn transform_node(&mut self, _context: &AnalyzerContext, node: &mut nodes::Node) -> Action {
+        match &node.data {
+            nodes::NodeData::Directive(directive) if self.names.contains(&directive.name) => {
+                Action::Unwrap
+            }
+            _ => Action::Keep,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn count_nodes(node: &mut nodes::Node, type_name: &str) -> usize {
+        let mut count = 0;
+        node.for_each(&mut |node: &mut nodes::Node| {
+            if node.data.type_name() == type_name {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    #[test]
+    fn unwrap_directives() {
+        let mut doc = nodes::tests::load_sample();
+        let text = doc.ast.get_text();
+        assert_eq!(count_nodes(&mut doc.ast, "directive"), 15);
+        let n_lists = count_nodes(&mut doc.ast, "list");
+
+        let names = vec!["list-table".to_owned()];
+        doc.ast.run_transform(&mut UnwrapDirectives::new(&names));
+
+        // Only the 13 list-table directives are removed; their contents remain in place
+        assert_eq!(count_nodes(&mut doc.ast, "directive"), 2);
+        assert_eq!(count_nodes(&mut doc.ast, "list"), n_lists);
+        assert_eq!(doc.ast.get_text(), text);
+    }
+
+    /// Replace each Text node with two, and drop every "literal" node.
+    struct DoubleText;
+
+    impl Transform for DoubleText {
+        fn transform_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) -> Action {
+            assert!(context.depth() > 0);
+            match &node.data {
+                nodes::NodeData::Text(_) => Action::Replace(vec![node.clone(), node.clone()]),
+                nodes::NodeData::Literal(_) => Action::Remove,
+                _ => Action::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn replace_and_remove() {
+        let mut doc = nodes::tests::load_sample();
+        let n_literals = count_nodes(&mut doc.ast, "literal");
+        assert!(n_literals > 0);
+        let n_text = count_nodes(&mut doc.ast, "text");
+
+        let mut literal_texts = 0;
+        doc.ast.for_each(&mut |node: &mut nodes::Node| {
+            if let nodes::NodeData::Literal(_) = node.data {
+                literal_texts += node
+                    .data
+                    .children()
+                    .iter()
+                    .filter(|child| child.data.type_name() == "text")
+                    .count();
+            }
+        });
+
+        doc.ast.run_transform(&mut DoubleText);
+        assert_eq!(count_nodes(&mut doc.ast, "literal"), 0);
+        assert_eq!(
+            count_nodes(&mut doc.ast, "text"),
+            (n_text - literal_texts) * 2
+        );
+    }
+
+    /// Unwrap every nested Root and Text node, recording the file each Text node was seen in.
+    #[derive(Default)]
+    struct UnwrapIncludes {
+        text_files: Vec<String>,
+    }
+
+    impl Transform for UnwrapIncludes {
+        fn transform_node(&mut self, context: &AnalyzerContext, node: &mut nodes::Node) -> Action {
+            match &node.data {
+                nodes::NodeData::Root(_) => Action::Unwrap,
+                nodes::NodeData::Text(_) => {
+                    self.text_files
+                        .push(context.get_current().unwrap().as_posix());
+                    Action::Unwrap
+                }
+                _ => Action::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn unwrap_includes() {
+        let mut ast: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "index.txt",
+            "children": [
+                {"type": "text", "position": {"start": {"line": 0}}, "value": "Before "},
+                {
+                    "type": "root",
+                    "position": {"start": {"line": 1}},
+                    "fileid": "includes/note.txt",
+                    "children": [
+                        {"type": "text", "position": {"start": {"line": 0}}, "value": "included "}
+                    ]
+                },
+                {"type": "text", "position": {"start": {"line": 2}}, "value": "after"}
+            ]
+        }))
+        .unwrap();
+
+        let mut transform = UnwrapIncludes::default();
+        ast.run_transform(&mut transform);
+
+        // Text nodes have no children to unwrap into, so they stay, and the included text is
+        // still attributed to the file it came from
+        assert_eq!(count_nodes(&mut ast, "root"), 1);
+        assert_eq!(ast.data.children().len(), 3);
+        assert_eq!(ast.get_text(), "Before included after");
+        assert_eq!(
+            transform.text_files,
+            vec!["index.txt", "includes/note.txt", "index.txt"]
+        );
+    }
+
+    #[test]
+    fn strip_comments() {
+        let mut ast: nodes::Node = bson::from_bson(bson::bson!({
+            "type": "root",
+            "position": {"start": {"line": 0}},
+            "fileid": "index.txt",
+            "children": [
+                {
+                    "type": "comment",
+                    "position": {"start": {"line": 0}},
+                    "children": [
+                        {"type": "text", "position": {"start": {"line": 0}}, "value": "TODO"}
+                    ]
+                },
+                {
+                    "type": "paragraph",
+                    "position": {"start": {"line": 1}},
+                    "children": [
+                        {"type": "text", "position": {"start": {"line": 1}}, "value": "Hello"},
+                        {"type": "comment", "position": {"start": {"line": 1}}, "children": []}
+                    ]
+                }
+            ]
+        }))
+        .unwrap();
+
+        ast.run_transform(&mut StripComments);
+        assert_eq!(count_nodes(&mut ast, "comment"), 0);
+        assert_eq!(count_nodes(&mut ast, "paragraph"), 1);
+        assert_eq!(ast.get_text(), "Hello");
+    }
+}